@@ -21,6 +21,7 @@ pub enum Command {
     Pass,
     Quit,
     Capa,
+    Stls,
     Greet,
     #[cfg(feature = "sasl")]
     Base64(String),
@@ -65,6 +66,7 @@ impl Command {
             "user" => User,
             "quit" => Quit,
             "capa" => Capa,
+            "stls" => Stls,
             "pass" => Pass
         )
     }
@@ -141,6 +141,24 @@ pub async fn new<S: Read + Write + Unpin + Send>(stream: S) -> Result<Client<S>>
     create_client_from_socket(socket).await
 }
 
+/// Creates a new pop3 client from a stream, which was upgraded to tls after [Client::stls].
+///
+/// The server doesn't greet again after the negotiation, so only the capabilities are requested, as the RFC requires.
+pub async fn new_after_stls<S: Read + Write + Unpin + Send>(stream: S) -> Result<Client<S>> {
+    let mut client = Client {
+        marked_as_del: Vec::new(),
+        capabilities: Vec::new(),
+        greeting: None,
+        read_greeting: true,
+        inner: Some(PopStream::new(stream)),
+        state: ClientState::Authentication,
+    };
+
+    client.update_capabilities().await;
+
+    Ok(client)
+}
+
 /// Create a new pop3 client with a tls connection.
 #[cfg(feature = "tls")]
 pub async fn connect<'a, A: ToSocketAddrs, D: AsRef<str>, C: Into<tls::TlsConnector<'a>>>(
@@ -198,6 +216,11 @@ impl<S: Read + Write + Unpin + Send> Client<S> {
         self.inner
     }
 
+    /// Convert this client into the raw underlying stream, e.g. to negotiate tls after [Client::stls].
+    pub fn into_stream(self) -> Option<S> {
+        self.inner.map(PopStream::into_inner)
+    }
+
     /// Check if the client is in the correct state.
     fn check_client_state(&self, state: ClientState) -> Result<()> {
         if self.state != state {
@@ -368,7 +391,11 @@ impl<S: Read + Write + Unpin + Send> Client<S> {
         let response = self.send_request(request).await?;
 
         match response {
-            Response::Message(resp) => Ok(resp),
+            Response::Message(resp) => {
+                self.marked_as_del.push(msg_number);
+
+                Ok(resp)
+            }
             _ => err!(
                 ErrorKind::UnexpectedResponse,
                 "Did not received the expected dele response"
@@ -685,6 +712,37 @@ impl<S: Read + Write + Unpin + Send> Client<S> {
         Ok((user_response_str, pass_response_str))
     }
 
+    /// ## STLS
+    /// Asks the server to begin a TLS negotiation. After a positive response the tls handshake should be performed on the underlying stream, see [Client::into_stream] and [new_after_stls].
+    /// ### Arguments: none
+    /// ### Restrictions:
+    /// - Only permitted in the AUTHORIZATION state
+    /// ### Possible Responses:
+    /// - +OK
+    /// - -ERR
+    ///
+    /// https://www.rfc-editor.org/rfc/rfc2595#section-4
+    pub async fn stls(&mut self) -> Result<Text> {
+        self.check_client_state(ClientState::Authentication)?;
+
+        self.has_read_greeting()?;
+
+        // Servers without CAPA may still support STLS, so it's checked only if capabilities are known
+        if !self.capabilities.is_empty() {
+            self.check_capability(vec![Capability::Stls])?;
+        }
+
+        let response = self.send_request(Stls).await?;
+
+        match response {
+            Response::Message(resp) => Ok(resp),
+            _ => err!(
+                ErrorKind::UnexpectedResponse,
+                "Did not received the expected stls response"
+            ),
+        }
+    }
+
     /// ## QUIT
     /// Quits the session
     ///
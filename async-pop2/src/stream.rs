@@ -183,6 +183,11 @@ impl<S: Read + Write + Unpin> PopStream<S> {
     pub fn last_activity(&self) -> Instant {
         self.last_activity
     }
+
+    /// Convert this stream into the raw underlying stream.
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
 }
 
 struct CommandQueue {
@@ -0,0 +1,295 @@
+//! Import of Thunderbird autoconfig documents
+//!
+//! Format is described at <https://wiki.mozilla.org/Thunderbird:Autoconfiguration:ConfigFileFormat>,
+//! providers database (ISPDB) lives at <https://github.com/thunderbird/autoconfig>
+
+use std::path::{Path, PathBuf};
+
+use roxmltree::{Document, Node};
+
+use crate::server_map::{
+    AuthMethod, Endpoint, Endpoints, Imap, Pop3, ProtocolEndpoint, Security, Server, ServerMap,
+};
+
+const EMAIL_DOMAIN_PLACEHOLDER: &str = "%EMAILDOMAIN%";
+
+#[derive(thiserror::Error, Debug)]
+pub enum AutoconfigError {
+    #[error("couldn't read autoconfig file {path}")]
+    Io {
+        path: PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
+
+    #[error("malformed xml")]
+    Xml(#[from] roxmltree::Error),
+
+    #[error("missing <{0}> element")]
+    MissingElement(&'static str),
+
+    #[error("invalid <{element}> value: {value}")]
+    InvalidValue {
+        element: &'static str,
+        value: String,
+    },
+}
+
+/// Parse `clientConfig` document into servers
+///
+/// Only IMAP and POP3 `incomingServer` entries are used, the first entry of each type wins.
+/// Providers without any of them are skipped
+pub fn parse(xml: &str) -> Result<Vec<Server>, AutoconfigError> {
+    let document = Document::parse(xml)?;
+
+    let mut servers = Vec::new();
+    for provider in document
+        .root_element()
+        .children()
+        .filter(|node| node.has_tag_name("emailProvider"))
+    {
+        servers.extend(parse_provider(provider)?);
+    }
+
+    Ok(servers)
+}
+
+fn parse_provider(provider: Node) -> Result<Vec<Server>, AutoconfigError> {
+    let domains: Vec<String> = children(provider, "domain")
+        .filter_map(|domain| domain.text())
+        .map(|domain| domain.trim().to_lowercase())
+        .collect();
+
+    let mut imap = None;
+    let mut pop3 = None;
+    for server in children(provider, "incomingServer") {
+        match server.attribute("type") {
+            Some("imap") if imap.is_none() => imap = Some(parse_incoming_server(server)?),
+            Some("pop3") if pop3.is_none() => pop3 = Some(parse_incoming_server(server)?),
+            _ => {}
+        }
+    }
+
+    let to_endpoints = |domain: &str| {
+        let resolve = |endpoint: &Endpoint| ProtocolEndpoint {
+            domain: endpoint.domain.replace(EMAIL_DOMAIN_PLACEHOLDER, domain),
+            ..endpoint.clone()
+        };
+
+        match (imap.as_ref().map(resolve), pop3.as_ref().map(resolve)) {
+            (Some(imap), Some(pop3)) => Some(Endpoints::Full {
+                pop3: Pop3(pop3),
+                imap: Imap(imap),
+            }),
+            (Some(imap), None) => Some(Endpoints::Imap { imap: Imap(imap) }),
+            (None, Some(pop3)) => Some(Endpoints::Pop3 { pop3: Pop3(pop3) }),
+            (None, None) => None,
+        }
+    };
+
+    let depends_on_domain = [&imap, &pop3]
+        .into_iter()
+        .flatten()
+        .any(|endpoint| endpoint.domain.contains(EMAIL_DOMAIN_PLACEHOLDER));
+
+    // Hostnames like `mail.%EMAILDOMAIN%` differ for every domain of provider
+    if depends_on_domain {
+        return Ok(domains
+            .into_iter()
+            .filter_map(|domain| {
+                Some(Server {
+                    endpoint: to_endpoints(&domain)?,
+                    domains: vec![domain],
+                })
+            })
+            .collect());
+    }
+
+    Ok(to_endpoints("")
+        .map(|endpoint| Server { domains, endpoint })
+        .into_iter()
+        .collect())
+}
+
+fn parse_incoming_server(server: Node) -> Result<Endpoint, AutoconfigError> {
+    let hostname = required_text(server, "hostname")?;
+
+    let port = required_text(server, "port")?;
+    let port = port.parse().map_err(|_| AutoconfigError::InvalidValue {
+        element: "port",
+        value: port.to_owned(),
+    })?;
+
+    let security = match required_text(server, "socketType")? {
+        "SSL" => Security::Tls,
+        "STARTTLS" => Security::StartTls,
+        "plain" => Security::Plain,
+        other => {
+            return Err(AutoconfigError::InvalidValue {
+                element: "socketType",
+                value: other.to_owned(),
+            })
+        }
+    };
+
+    let auth = children(server, "authentication")
+        .filter_map(|auth| auth.text())
+        .map(|auth| match auth.trim() {
+            "password-cleartext" | "plain" => AuthMethod::PasswordCleartext,
+            "password-encrypted" | "secure" => AuthMethod::PasswordEncrypted,
+            "OAuth2" => AuthMethod::OAuth2,
+            "TLS-client-cert" => AuthMethod::ClientCertificate,
+            _ => AuthMethod::Other,
+        })
+        .collect();
+
+    Ok(ProtocolEndpoint {
+        domain: hostname.to_owned(),
+        port,
+        security,
+        auth,
+    })
+}
+
+fn children<'a, 'input>(
+    node: Node<'a, 'input>,
+    tag: &'static str,
+) -> impl Iterator<Item = Node<'a, 'input>> {
+    node.children().filter(move |child| child.has_tag_name(tag))
+}
+
+fn required_text<'a>(node: Node<'a, '_>, tag: &'static str) -> Result<&'a str, AutoconfigError> {
+    children(node, tag)
+        .next()
+        .and_then(|child| child.text())
+        .map(str::trim)
+        .ok_or(AutoconfigError::MissingElement(tag))
+}
+
+impl ServerMap {
+    /// Add servers from autoconfig document
+    pub fn import_autoconfig(&mut self, xml: &str) -> Result<(), AutoconfigError> {
+        for server in parse(xml)? {
+            self.add_server(server);
+        }
+
+        Ok(())
+    }
+
+    /// Add servers from a single autoconfig file, e.g. `gmail.com.xml`
+    pub fn import_autoconfig_file(
+        &mut self,
+        path: impl AsRef<Path>,
+    ) -> Result<(), AutoconfigError> {
+        let path = path.as_ref();
+        let xml = std::fs::read_to_string(path).map_err(|source| AutoconfigError::Io {
+            path: path.to_owned(),
+            source,
+        })?;
+
+        self.import_autoconfig(&xml)
+    }
+
+    /// Add servers from every file of directory, e.g. `ispdb` directory of ISPDB checkout
+    ///
+    /// Malformed files are logged and skipped. Returns number of imported files
+    pub fn import_autoconfig_dir(
+        &mut self,
+        dir: impl AsRef<Path>,
+    ) -> Result<usize, AutoconfigError> {
+        let dir = dir.as_ref();
+        let io_error = |source| AutoconfigError::Io {
+            path: dir.to_owned(),
+            source,
+        };
+
+        let mut imported = 0;
+        for entry in std::fs::read_dir(dir).map_err(io_error)? {
+            let path = entry.map_err(io_error)?.path();
+            if !path.is_file() {
+                continue;
+            }
+
+            match self.import_autoconfig_file(&path) {
+                Ok(()) => imported += 1,
+                Err(err) => {
+                    tracing::warn!(path = %path.display(), %err, "skipping autoconfig file")
+                }
+            }
+        }
+
+        Ok(imported)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let xml = r#"<?xml version="1.0"?>
+            <clientConfig version="1.1">
+              <emailProvider id="example.com">
+                <domain>example.com</domain>
+                <domain>example.org</domain>
+                <incomingServer type="imap">
+                  <hostname>imap.example.com</hostname>
+                  <port>993</port>
+                  <socketType>SSL</socketType>
+                  <authentication>OAuth2</authentication>
+                  <authentication>password-cleartext</authentication>
+                </incomingServer>
+                <incomingServer type="imap">
+                  <hostname>imap.example.com</hostname>
+                  <port>143</port>
+                  <socketType>STARTTLS</socketType>
+                </incomingServer>
+                <incomingServer type="pop3">
+                  <hostname>pop.example.com</hostname>
+                  <port>110</port>
+                  <socketType>STARTTLS</socketType>
+                </incomingServer>
+              </emailProvider>
+            </clientConfig>"#;
+
+        let servers = parse(xml).unwrap();
+        assert_eq!(servers.len(), 1);
+        assert_eq!(servers[0].domains, ["example.com", "example.org"]);
+
+        let imap = &servers[0].endpoint.get_imap().unwrap().0;
+        assert_eq!(imap.port, 993);
+        assert_eq!(imap.security, Security::Tls);
+        assert_eq!(
+            imap.auth,
+            [AuthMethod::OAuth2, AuthMethod::PasswordCleartext]
+        );
+
+        let pop3 = &servers[0].endpoint.get_pop3().unwrap().0;
+        assert_eq!(pop3.domain, "pop.example.com");
+        assert_eq!(pop3.security, Security::StartTls);
+    }
+
+    #[test]
+    fn test_parse_domain_placeholder() {
+        let xml = r#"<clientConfig version="1.1">
+              <emailProvider id="hoster">
+                <domain>a.com</domain>
+                <domain>b.com</domain>
+                <incomingServer type="pop3">
+                  <hostname>mail.%EMAILDOMAIN%</hostname>
+                  <port>995</port>
+                  <socketType>SSL</socketType>
+                </incomingServer>
+              </emailProvider>
+            </clientConfig>"#;
+
+        let servers = parse(xml).unwrap();
+        assert_eq!(servers.len(), 2);
+        assert_eq!(servers[1].domains, ["b.com"]);
+        assert_eq!(
+            servers[1].endpoint.get_pop3().unwrap().0.domain,
+            "mail.b.com"
+        );
+    }
+}
@@ -1,30 +1,164 @@
-use std::sync::Arc;
+use std::{
+    future::Future,
+    io,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
 
 use chrono::{DateTime, Utc};
 use proxied::Proxy;
-use rustls::pki_types::ServerName;
-use tokio_rustls::TlsConnector;
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    time::Sleep,
+};
 
-use crate::{Conn, Error};
-fn create_connector() -> TlsConnector {
-    let root_store = rustls::RootCertStore {
-        roots: webpki_roots::TLS_SERVER_ROOTS.iter().cloned().collect(),
-    };
+use crate::{tls::TlsOptions, Conn, Error, TimeoutPhase, Timeouts};
+
+/// Runs `future`, failing with `Error::Timeout` of `phase` if it takes longer than `timeout`
+pub(crate) async fn with_timeout<T>(
+    phase: TimeoutPhase,
+    timeout: Option<Duration>,
+    future: impl Future<Output = Result<T, Error>>,
+) -> Result<T, Error> {
+    match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, future)
+            .await
+            .map_err(|_| Error::Timeout { phase })?,
+        None => future.await,
+    }
+}
+
+/// Read timeout of connection, which can be suspended while server is expected to stay silent, e.g. in IDLE
+#[derive(Clone, Debug)]
+pub(crate) struct ReadTimeout {
+    timeout: Option<Duration>,
+    suspended: Arc<AtomicU64>,
+}
+
+impl ReadTimeout {
+    pub(crate) fn new(timeout: Option<Duration>) -> Self {
+        Self {
+            timeout,
+            suspended: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// Suspends timeout until the guard is dropped
+    pub(crate) fn suspend(&self) -> impl Drop + Send {
+        self.suspended.fetch_add(1, Ordering::SeqCst);
+
+        struct Resume(Arc<AtomicU64>);
+        impl Drop for Resume {
+            fn drop(&mut self) {
+                self.0.fetch_sub(1, Ordering::SeqCst);
+            }
+        }
+
+        Resume(self.suspended.clone())
+    }
+
+    fn get(&self) -> Option<Duration> {
+        match self.suspended.load(Ordering::SeqCst) {
+            0 => self.timeout,
+            _ => None,
+        }
+    }
+}
+
+/// Connection, which fails reads with `io::ErrorKind::TimedOut`, if server doesn't send anything for too long
+#[derive(Debug)]
+struct TimedConn {
+    inner: Box<dyn Conn>,
+    read_timeout: ReadTimeout,
+    deadline: Option<Pin<Box<Sleep>>>,
+}
+
+impl AsyncRead for TimedConn {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+
+        if let Poll::Ready(result) = Pin::new(&mut this.inner).poll_read(cx, buf) {
+            this.deadline = None;
+            return Poll::Ready(result);
+        }
+
+        let Some(timeout) = this.read_timeout.get() else {
+            this.deadline = None;
+            return Poll::Pending;
+        };
+
+        let deadline = this
+            .deadline
+            .get_or_insert_with(|| Box::pin(tokio::time::sleep(timeout)));
+        match deadline.as_mut().poll(cx) {
+            Poll::Ready(()) => {
+                this.deadline = None;
+                Poll::Ready(Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "server didn't respond in time",
+                )))
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl AsyncWrite for TimedConn {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        // New command starts a new wait. Deadline of a dropped read would otherwise stay armed
+        self.deadline = None;
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
 
-    let config = rustls::client::ClientConfig::builder()
-        .with_root_certificates(Arc::new(root_store))
-        .with_no_client_auth();
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
 
-    TlsConnector::from(Arc::new(config))
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
 }
 
-pub(crate) async fn connect_maybe_proxied_stream_tls(
+/// Connects within `timeouts.connect`, reads of the connection are limited by `read_timeout`
+pub(crate) async fn connect_maybe_proxied_stream(
     domain: String,
     port: u16,
     proxy: Option<Proxy>,
+    timeouts: &Timeouts,
+    read_timeout: ReadTimeout,
 ) -> Result<Box<dyn Conn>, Error> {
-    let connector = create_connector();
+    let tunnel = with_timeout(
+        TimeoutPhase::Connect,
+        timeouts.connect,
+        connect_tunnel(domain, port, proxy),
+    )
+    .await?;
+
+    Ok(Box::new(TimedConn {
+        inner: tunnel,
+        read_timeout,
+        deadline: None,
+    }))
+}
 
+async fn connect_tunnel(
+    domain: String,
+    port: u16,
+    proxy: Option<Proxy>,
+) -> Result<Box<dyn Conn>, Error> {
     let tunnel: Box<dyn Conn> = match proxy {
         Some(proxy) => Box::new(
             proxy
@@ -46,9 +180,71 @@ pub(crate) async fn connect_maybe_proxied_stream_tls(
             Box::new(stream)
         }
     };
-    let stream = connector
-        .connect(ServerName::try_from(domain.clone()).unwrap(), tunnel)
-        .await?;
+
+    Ok(tunnel)
+}
+
+/// Performs TLS handshake over already established `stream` within `timeouts.tls_handshake`
+pub(crate) async fn upgrade_tls(
+    domain: String,
+    stream: Box<dyn Conn>,
+    tls: &TlsOptions,
+    timeouts: &Timeouts,
+) -> Result<Box<dyn Conn>, Error> {
+    let (connector, server_name) = tls.connector(&domain)?;
+
+    let handshake = async {
+        connector
+            .connect(server_name, stream)
+            .await
+            .map_err(|err| match Error::from(err) {
+                Error::Socket(err) => Error::TlsHandshake(err),
+                err => err,
+            })
+    };
+    let stream = with_timeout(
+        TimeoutPhase::TlsHandshake,
+        timeouts.tls_handshake,
+        handshake,
+    )
+    .await?;
 
     Ok(Box::new(stream))
 }
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_dropped_read() {
+        let (client, mut server) = tokio::io::duplex(64);
+        let mut conn = TimedConn {
+            inner: Box::new(client),
+            read_timeout: ReadTimeout::new(Some(Duration::from_millis(50))),
+            deadline: None,
+        };
+        let mut buf = [0; 16];
+
+        // Read is abandoned before its deadline, e.g. by an outer timeout
+        let abandoned = tokio::time::timeout(Duration::from_millis(10), conn.read(&mut buf)).await;
+        assert!(abandoned.is_err());
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        conn.write_all(b"NOOP\r\n").await.unwrap();
+        let reply = async {
+            let mut command = [0; 6];
+            server.read_exact(&mut command).await.unwrap();
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            server.write_all(b"+OK\r\n").await.unwrap();
+        };
+        let (read, ()) = tokio::join!(conn.read(&mut buf), reply);
+        assert_eq!(&buf[..read.unwrap()], b"+OK\r\n");
+
+        // Silent server still times out
+        let err = conn.read(&mut buf).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    }
+}
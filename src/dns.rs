@@ -0,0 +1,415 @@
+use std::sync::{Arc, LazyLock};
+
+use hickory_resolver::{
+    error::{ResolveError, ResolveErrorKind},
+    TokioAsyncResolver,
+};
+use rand::Rng;
+
+use crate::{
+    server_map::{Endpoint, Endpoints, Imap, Pop3, ProtocolEndpoint, Security, ServerMap},
+    Error,
+};
+
+/// Mail exchanger of domain
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Mx {
+    /// Lower is preferred
+    pub preference: u16,
+    pub exchange: String,
+}
+
+/// Service record of RFC 2782
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Srv {
+    /// Lower is preferred
+    pub priority: u16,
+    /// Among records with the same priority, chance of being chosen is proportional to it
+    pub weight: u16,
+    pub port: u16,
+    /// `.` means that service is decidedly not available
+    pub target: String,
+}
+
+/// DNS lookups used to find servers of domains missing in `ServerMap`
+///
+/// Replace it in `ConnectOptions` to use custom upstream or a stand-in in tests
+#[async_trait::async_trait]
+pub trait DnsResolver: Send + Sync {
+    /// MX records of `domain`, empty if it has none
+    async fn mx(&self, domain: &str) -> Result<Vec<Mx>, Error>;
+
+    /// SRV records with `name`, e.g. `_imaps._tcp.example.com`, empty if it has none
+    async fn srv(&self, name: &str) -> Result<Vec<Srv>, Error>;
+}
+
+fn lookup_error(name: &str, err: ResolveError) -> Result<(), Error> {
+    match err.kind() {
+        ResolveErrorKind::NoRecordsFound { .. } => Ok(()),
+        _ => Err(Error::DnsLookup {
+            domain: name.to_owned(),
+            source: err.into(),
+        }),
+    }
+}
+
+/// Resolver, configured from `/etc/resolv.conf` or its platform counterpart
+#[derive(Clone)]
+pub struct HickoryResolver {
+    inner: TokioAsyncResolver,
+}
+
+impl HickoryResolver {
+    /// Falls back to public resolvers if system configuration can't be read
+    pub fn new() -> Self {
+        let inner = TokioAsyncResolver::tokio_from_system_conf().unwrap_or_else(|err| {
+            tracing::warn!(%err, "couldn't read system dns config, using defaults");
+            TokioAsyncResolver::tokio(Default::default(), Default::default())
+        });
+
+        Self { inner }
+    }
+}
+
+impl Default for HickoryResolver {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[async_trait::async_trait]
+impl DnsResolver for HickoryResolver {
+    async fn mx(&self, domain: &str) -> Result<Vec<Mx>, Error> {
+        let lookup = match self.inner.mx_lookup(domain).await {
+            Ok(lookup) => lookup,
+            Err(err) => return lookup_error(domain, err).map(|()| Vec::new()),
+        };
+
+        Ok(lookup
+            .iter()
+            .map(|mx| Mx {
+                preference: mx.preference(),
+                exchange: mx.exchange().to_utf8(),
+            })
+            .collect())
+    }
+
+    async fn srv(&self, name: &str) -> Result<Vec<Srv>, Error> {
+        let lookup = match self.inner.srv_lookup(name).await {
+            Ok(lookup) => lookup,
+            Err(err) => return lookup_error(name, err).map(|()| Vec::new()),
+        };
+
+        Ok(lookup
+            .iter()
+            .map(|srv| Srv {
+                priority: srv.priority(),
+                weight: srv.weight(),
+                port: srv.port(),
+                target: srv.target().to_utf8(),
+            })
+            .collect())
+    }
+}
+
+static DEFAULT_RESOLVER: LazyLock<Arc<dyn DnsResolver>> =
+    LazyLock::new(|| Arc::new(HickoryResolver::new()));
+
+/// Shared `HickoryResolver`, created on first use
+pub fn default_resolver() -> Arc<dyn DnsResolver> {
+    DEFAULT_RESOLVER.clone()
+}
+
+/// Providers, whose MX hosts differ from their mail domains
+const MX_PROVIDERS: &[(&str, &str)] = &[
+    ("google.com", "gmail.com"),
+    ("googlemail.com", "gmail.com"),
+    ("outlook.com", "outlook.com"),
+    ("hotmail.com", "outlook.com"),
+    ("yahoodns.net", "yahoo.com"),
+    ("zoho.com", "zoho.com"),
+    ("zoho.eu", "zoho.eu"),
+    ("icloud.com", "icloud.com"),
+    ("yandex.net", "yandex.ru"),
+    ("mail.ru", "mail.ru"),
+    ("fastmail.com", "fastmail.com"),
+    ("messagingengine.com", "fastmail.com"),
+    ("protonmail.ch", "proton.me"),
+    ("gmx.net", "gmx.net"),
+    ("web.de", "web.de"),
+];
+
+/// `host` itself and its parent domains, down to second level
+fn with_parents(host: &str) -> impl Iterator<Item = &str> {
+    std::iter::successors(Some(host), |host| {
+        let (_, parent) = host.split_once('.')?;
+        parent.contains('.').then_some(parent)
+    })
+}
+
+impl ServerMap {
+    /// Endpoints of provider, hosting domain with `mx` records
+    ///
+    /// Every MX host, in order of preference, is looked up with its parent domains,
+    /// then matched against built-in list of providers, e.g. `aspmx.l.google.com` resolves to `gmail.com` entry
+    pub fn get_by_mx(&self, mx: &[Mx]) -> Option<&'static Endpoints> {
+        let mut mx = mx.to_vec();
+        mx.sort_by_key(|mx| mx.preference);
+
+        mx.iter().find_map(|mx| {
+            let host = mx.exchange.trim_end_matches('.').to_lowercase();
+
+            let endpoints = with_parents(&host).find_map(|domain| {
+                self.get_by_domain(domain).or_else(|| {
+                    MX_PROVIDERS
+                        .iter()
+                        .find(|(mx_domain, _)| *mx_domain == domain)
+                        .and_then(|(_, provider)| self.get_by_domain(*provider))
+                })
+            });
+            endpoints
+        })
+    }
+}
+
+/// Record to try first, as RFC 2782 orders them: lowest priority, then weighted random choice
+///
+/// `roll` returns a number in `0..=total` for total weight of the candidates
+fn pick_srv(records: Vec<Srv>, roll: impl FnOnce(u32) -> u32) -> Option<Srv> {
+    let priority = records.iter().map(|srv| srv.priority).min()?;
+    let mut candidates: Vec<Srv> = records
+        .into_iter()
+        .filter(|srv| srv.priority == priority)
+        .collect();
+    // Records with zero weight go first, so they have a small chance to be chosen
+    candidates.sort_by_key(|srv| srv.weight != 0);
+
+    let total = candidates.iter().map(|srv| u32::from(srv.weight)).sum();
+    let roll = roll(total);
+    let mut sum = 0;
+    candidates.into_iter().find(|srv| {
+        sum += u32::from(srv.weight);
+        sum >= roll
+    })
+}
+
+/// Endpoint of the first service, which is available, among `services` with their security
+///
+/// Lone `.` target means that protocol is decidedly not available, so the rest aren't tried
+async fn discover_service(
+    domain: &str,
+    services: &[(&str, Security)],
+    resolver: &dyn DnsResolver,
+) -> Result<Option<Endpoint>, Error> {
+    for (service, security) in services {
+        let records: Vec<Srv> = resolver
+            .srv(&format!("{service}._tcp.{domain}"))
+            .await?
+            .into_iter()
+            .map(|srv| Srv {
+                target: srv.target.trim_end_matches('.').to_owned(),
+                ..srv
+            })
+            .collect();
+
+        if let [srv] = records.as_slice() {
+            if srv.target.is_empty() {
+                return Ok(None);
+            }
+        }
+
+        let records = records
+            .into_iter()
+            .filter(|srv| !srv.target.is_empty())
+            .collect();
+        let picked = pick_srv(records, |total| rand::thread_rng().gen_range(0..=total));
+
+        if let Some(srv) = picked {
+            return Ok(Some(ProtocolEndpoint {
+                domain: srv.target,
+                port: srv.port,
+                security: *security,
+                auth: Vec::new(),
+            }));
+        }
+    }
+
+    Ok(None)
+}
+
+/// Discover endpoints of `domain` with SRV records of RFC 6186
+///
+/// Implicit TLS services (`_imaps`, `_pop3s`) are preferred over STARTTLS ones (`_imap`, `_pop3`)
+pub async fn discover_srv(
+    domain: &str,
+    resolver: &dyn DnsResolver,
+) -> Result<Option<Endpoints>, Error> {
+    let imap = discover_service(
+        domain,
+        &[("_imaps", Security::Tls), ("_imap", Security::StartTls)],
+        resolver,
+    )
+    .await?;
+    let pop3 = discover_service(
+        domain,
+        &[("_pop3s", Security::Tls), ("_pop3", Security::StartTls)],
+        resolver,
+    )
+    .await?;
+
+    Ok(match (imap, pop3) {
+        (Some(imap), Some(pop3)) => Some(Endpoints::Full {
+            pop3: Pop3(pop3),
+            imap: Imap(imap),
+        }),
+        (Some(imap), None) => Some(Endpoints::Imap { imap: Imap(imap) }),
+        (None, Some(pop3)) => Some(Endpoints::Pop3 { pop3: Pop3(pop3) }),
+        (None, None) => None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server_map::Server;
+
+    struct StubResolver;
+
+    #[async_trait::async_trait]
+    impl DnsResolver for StubResolver {
+        async fn mx(&self, domain: &str) -> Result<Vec<Mx>, Error> {
+            Ok(match domain {
+                "hosted-by-google.org" => vec![Mx {
+                    preference: 1,
+                    exchange: "aspmx.l.google.com.".to_owned(),
+                }],
+                _ => Vec::new(),
+            })
+        }
+
+        async fn srv(&self, name: &str) -> Result<Vec<Srv>, Error> {
+            let srv = |priority, weight, target: &str| Srv {
+                priority,
+                weight,
+                port: 993,
+                target: target.to_owned(),
+            };
+
+            Ok(match name {
+                "_imaps._tcp.example.com" => vec![
+                    srv(20, 100, "backup.example.com."),
+                    srv(10, 1, "light.example.com."),
+                    srv(10, 5, "heavy.example.com."),
+                ],
+                "_pop3s._tcp.example.com" => vec![srv(0, 0, ".")],
+                "_pop3._tcp.example.com" => vec![srv(0, 0, "plain.example.com.")],
+                _ => Vec::new(),
+            })
+        }
+    }
+
+    fn gmail_map() -> ServerMap {
+        let mut map = ServerMap::new();
+        map.add_server(Server {
+            domains: vec!["gmail.com".to_owned()],
+            endpoint: Endpoints::Imap {
+                imap: Imap(ProtocolEndpoint {
+                    domain: "imap.gmail.com".to_owned(),
+                    port: 993,
+                    security: Security::Tls,
+                    auth: Vec::new(),
+                }),
+            },
+        });
+        map
+    }
+
+    #[test]
+    fn test_get_by_mx() {
+        let map = gmail_map();
+
+        let mx = |preference, exchange: &str| Mx {
+            preference,
+            exchange: exchange.to_owned(),
+        };
+
+        let google = [
+            mx(10, "alt1.aspmx.l.google.com."),
+            mx(1, "aspmx.l.google.com."),
+        ];
+        assert!(map.get_by_mx(&google).is_some());
+
+        let unknown = [mx(10, "mx.unknown-hoster.net.")];
+        assert!(map.get_by_mx(&unknown).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_discover_srv() {
+        let endpoints = discover_srv("example.com", &StubResolver)
+            .await
+            .unwrap()
+            .unwrap();
+
+        let Endpoints::Imap { imap } = endpoints else {
+            panic!("pop3 is unavailable, got {endpoints:?}");
+        };
+        assert!(["light.example.com", "heavy.example.com"].contains(&imap.0.domain.as_str()));
+        assert_eq!(imap.0.security, Security::Tls);
+    }
+
+    #[test]
+    fn test_pick_srv() {
+        let srv = |priority, weight, target: &str| Srv {
+            priority,
+            weight,
+            port: 993,
+            target: target.to_owned(),
+        };
+        let records = vec![
+            srv(20, 100, "backup"),
+            srv(10, 3, "heavy"),
+            srv(10, 1, "light"),
+            srv(10, 0, "zero"),
+        ];
+        let pick = |roll| {
+            pick_srv(records.clone(), |total| {
+                assert_eq!(total, 4);
+                roll
+            })
+        };
+
+        assert_eq!(pick(0).unwrap().target, "zero");
+        assert_eq!(pick(1).unwrap().target, "heavy");
+        assert_eq!(pick(3).unwrap().target, "heavy");
+        assert_eq!(pick(4).unwrap().target, "light");
+        assert!(pick_srv(Vec::new(), |_| 0).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_find_endpoints() {
+        let map = gmail_map();
+        let mut options = crate::ConnectOptions::default();
+
+        let err = crate::find_endpoints("example.com", &map, &options)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::ServerNotFound { .. }));
+
+        options.resolver = Some(Arc::new(StubResolver));
+
+        let endpoints = crate::find_endpoints("hosted-by-google.org", &map, &options)
+            .await
+            .unwrap();
+        assert_eq!(endpoints.get_imap().unwrap().0.domain, "imap.gmail.com");
+
+        let endpoints = crate::find_endpoints("example.com", &map, &options)
+            .await
+            .unwrap();
+        assert!(endpoints.get_pop3().is_none());
+
+        let err = crate::find_endpoints("unknown.org", &map, &options)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::ServerNotFound { .. }));
+    }
+}
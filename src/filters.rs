@@ -1,16 +1,35 @@
-use std::{borrow::Cow, ops::Deref};
+use std::{ops::Deref, sync::Arc};
 
-use chrono::{DateTime, Utc};
-use date::DateFilter;
-use logical::{And, Or};
-use sender::Sender;
+use attachment::{AttachmentName, AttachmentType, HasAttachment};
+use body::BodyContains;
+use chrono::{DateTime, TimeDelta, Utc};
+use date::{DateFilter, DateSource};
+use header::Header;
+use logical::{And, Not, Or};
+pub use matching::{MatchOptions, Normalization, Pattern};
+pub use query::{Query, QueryError, QueryTerm};
+use recipient::Recipient;
+use search::SearchCriteria;
+use sender::{FromAddress, Sender};
+use size::Size;
+use spam::Spam;
+pub use spec::{FilterSpec, FilterSpecError};
 use subject::{Subject, SubjectContains};
+use text::TextContains;
 
 use crate::OwnedMessage;
 
 pub trait Filter: Send + Sync {
     fn filter(&self, msg: &OwnedMessage) -> bool;
 
+    /// IMAP SEARCH criteria, matching at least every message accepted by this filter
+    ///
+    /// Used to narrow down downloads on server side, `filter` is still applied to fetched messages.
+    /// `None` means that filter can't be expressed as criteria, so every message has to be fetched
+    fn search_criteria(&self) -> Option<SearchCriteria> {
+        None
+    }
+
     fn dynamize(self) -> Box<dyn Filter>
     where
         Self: Sized + 'static,
@@ -19,9 +38,13 @@ pub trait Filter: Send + Sync {
     }
 }
 
-impl<'a, T: Filter> Filter for &'a T {
-    fn filter(&self, _: &OwnedMessage) -> bool {
-        todo!()
+impl<T: Filter> Filter for &T {
+    fn filter(&self, msg: &OwnedMessage) -> bool {
+        (*self).filter(msg)
+    }
+
+    fn search_criteria(&self) -> Option<SearchCriteria> {
+        (*self).search_criteria()
     }
 }
 
@@ -35,18 +58,35 @@ impl Filter for Box<dyn Filter> {
     fn filter(&self, msg: &OwnedMessage) -> bool {
         self.deref().filter(msg)
     }
+
+    fn search_criteria(&self) -> Option<SearchCriteria> {
+        self.deref().search_criteria()
+    }
+}
+
+/// Lets one filter be shared by many readers, e.g. in `MailboxPool`
+impl<T: Filter + ?Sized> Filter for Arc<T> {
+    fn filter(&self, msg: &OwnedMessage) -> bool {
+        self.deref().filter(msg)
+    }
+
+    fn search_criteria(&self) -> Option<SearchCriteria> {
+        self.deref().search_criteria()
+    }
 }
 
 macro_rules! define_impl_ext {
     {
         impl $filters:ident {
             $(
+                $(#[$meta:meta])*
                 pub fn $name:ident($($arg_name:ident: $arg_type:ty),*) -> impl Filter $name_block:block
             )*
         }
     } => {
         impl $filters {
             $(
+                $(#[$meta])*
                 pub fn $name($($arg_name: $arg_type),*) -> impl Filter $name_block
             )*
         }
@@ -97,26 +137,118 @@ impl Filters {
 
 define_impl_ext! {
     impl Filters {
-        pub fn subject(s: impl Into<Cow<'static, str>>) -> impl Filter {
+        pub fn subject(s: impl Into<Pattern>) -> impl Filter {
             Subject::new(s.into())
         }
 
-        pub fn subject_contains(s: impl Into<Cow<'static, str>>) -> impl Filter {
+        pub fn subject_contains(s: impl Into<Pattern>) -> impl Filter {
             SubjectContains::new(s.into())
         }
 
-        pub fn sender(s: impl Into<String>) -> impl Filter {
+        pub fn sender(s: impl Into<Pattern>) -> impl Filter {
             Sender::new(s.into())
         }
 
+        /// Any address of `From:` header contains `s`, ignoring ASCII case
+        pub fn from_address(s: impl Into<Pattern>) -> impl Filter {
+            FromAddress::new(s.into())
+        }
+
         pub fn sent_since(s: impl Into<DateTime<Utc>>) -> impl Filter {
             DateFilter::since(s.into())
         }
+
+        pub fn sent_before(s: impl Into<DateTime<Utc>>) -> impl Filter {
+            DateFilter::before(s.into())
+        }
+
+        pub fn sent_between(
+            since: impl Into<DateTime<Utc>>,
+            before: impl Into<DateTime<Utc>>
+        ) -> impl Filter {
+            And::new(DateFilter::since(since.into()), DateFilter::before(before.into()))
+        }
+
+        /// Sent not earlier than `period` before the moment of filtering, e.g. last 10 minutes
+        pub fn sent_within(period: TimeDelta) -> impl Filter {
+            DateFilter::within(period)
+        }
+
+        /// Like `sent_since`, but dated by topmost `Received:` header instead of sender-controlled `Date:`
+        pub fn received_since(s: impl Into<DateTime<Utc>>) -> impl Filter {
+            DateFilter::since(s.into()).source(DateSource::Received)
+        }
+
+        pub fn received_before(s: impl Into<DateTime<Utc>>) -> impl Filter {
+            DateFilter::before(s.into()).source(DateSource::Received)
+        }
+
+        pub fn received_between(
+            since: impl Into<DateTime<Utc>>,
+            before: impl Into<DateTime<Utc>>
+        ) -> impl Filter {
+            And::new(
+                DateFilter::since(since.into()).source(DateSource::Received),
+                DateFilter::before(before.into()).source(DateSource::Received),
+            )
+        }
+
+        pub fn received_within(period: TimeDelta) -> impl Filter {
+            DateFilter::within(period).source(DateSource::Received)
+        }
+
+        pub fn not(filter: impl Filter) -> impl Filter {
+            Not::new(filter)
+        }
+
+        pub fn text_contains(s: impl Into<Pattern>) -> impl Filter {
+            TextContains::new(s.into())
+        }
+
+        pub fn spam() -> impl Filter {
+            Spam
+        }
+
+        pub fn recipient(s: impl Into<Pattern>) -> impl Filter {
+            Recipient::new(s.into())
+        }
+
+        pub fn header(name: impl Into<String>, value: impl Into<Pattern>) -> impl Filter {
+            Header::equals(name.into(), value.into())
+        }
+
+        pub fn header_contains(name: impl Into<String>, value: impl Into<Pattern>) -> impl Filter {
+            Header::contains(name.into(), value.into())
+        }
+
+        pub fn body_contains(s: impl Into<Pattern>) -> impl Filter {
+            BodyContains::new(s.into())
+        }
+
+        pub fn has_attachment() -> impl Filter {
+            HasAttachment
+        }
+
+        pub fn attachment_name(s: impl Into<Pattern>) -> impl Filter {
+            AttachmentName::new(s.into())
+        }
+
+        pub fn attachment_type(mime: impl Into<String>) -> impl Filter {
+            AttachmentType::new(mime.into())
+        }
+
+        pub fn larger(bytes: u64) -> impl Filter {
+            Size::larger(bytes)
+        }
+
+        pub fn smaller(bytes: u64) -> impl Filter {
+            Size::smaller(bytes)
+        }
     }
 }
 
 mod logical {
-    use super::Filter;
+    use super::{search::SearchCriteria, Filter};
 
     pub struct And<First, Second> {
         first_filter: First,
@@ -136,6 +268,38 @@ mod logical {
         fn filter(&self, msg: &crate::OwnedMessage) -> bool {
             self.first_filter.filter(msg) && self.second_filter.filter(msg)
         }
+
+        fn search_criteria(&self) -> Option<SearchCriteria> {
+            match (
+                self.first_filter.search_criteria(),
+                self.second_filter.search_criteria(),
+            ) {
+                (Some(first), Some(second)) => Some(SearchCriteria::and(first, second)),
+                (first, second) => first.or(second),
+            }
+        }
+    }
+
+    /// Accepts messages, rejected by inner filter
+    pub struct Not<Inner> {
+        inner: Inner,
+    }
+
+    impl<I> Not<I> {
+        pub fn new(inner: I) -> Self {
+            Self { inner }
+        }
+    }
+
+    impl<Inner: Filter> Filter for Not<Inner> {
+        fn filter(&self, msg: &crate::OwnedMessage) -> bool {
+            !self.inner.filter(msg)
+        }
+
+        // Criteria of inner filter may match more than it accepts, so its negation could miss messages
+        fn search_criteria(&self) -> Option<SearchCriteria> {
+            None
+        }
     }
 
     pub struct Or<First, Second> {
@@ -156,18 +320,23 @@ mod logical {
         fn filter(&self, msg: &crate::OwnedMessage) -> bool {
             self.first_filter.filter(msg) || self.second_filter.filter(msg)
         }
+
+        fn search_criteria(&self) -> Option<SearchCriteria> {
+            Some(SearchCriteria::or(
+                self.first_filter.search_criteria()?,
+                self.second_filter.search_criteria()?,
+            ))
+        }
     }
 }
 mod subject {
-    use std::borrow::Cow;
-
-    use super::Filter;
+    use super::{search::SearchCriteria, Filter, Pattern};
 
     pub struct Subject {
-        subject: Cow<'static, str>,
+        subject: Pattern,
     }
     impl Subject {
-        pub fn new(subject: Cow<'static, str>) -> Self {
+        pub fn new(subject: Pattern) -> Self {
             Self { subject }
         }
     }
@@ -178,16 +347,20 @@ mod subject {
                 return false;
             };
 
-            msg_subject == self.subject.as_ref()
+            self.subject.is(msg_subject)
+        }
+
+        fn search_criteria(&self) -> Option<SearchCriteria> {
+            SearchCriteria::subject(self.subject.search_text()?)
         }
     }
 
     pub struct SubjectContains {
-        pattern: Cow<'static, str>,
+        pattern: Pattern,
     }
 
     impl SubjectContains {
-        pub fn new(pattern: Cow<'static, str>) -> Self {
+        pub fn new(pattern: Pattern) -> Self {
             Self { pattern }
         }
     }
@@ -198,76 +371,802 @@ mod subject {
                 return false;
             };
 
-            msg_subject.contains(self.pattern.as_ref())
+            self.pattern.is_in(msg_subject)
+        }
+
+        fn search_criteria(&self) -> Option<SearchCriteria> {
+            SearchCriteria::subject(self.pattern.search_text()?)
         }
     }
 }
 
 mod sender {
-    use super::Filter;
+    use super::{search::SearchCriteria, Filter, Pattern};
 
     pub struct Sender {
-        sender: String,
+        sender: Pattern,
     }
 
     impl Sender {
-        pub fn new(name: String) -> Self {
+        pub fn new(name: Pattern) -> Self {
             Self { sender: name }
         }
     }
 
     impl Filter for Sender {
         fn filter(&self, msg: &crate::OwnedMessage) -> bool {
-            msg.sender().is_some_and(|a| a.contains(&self.sender))
+            // Addresses are compared case-insensitively regardless of options, like before them
+            msg.sender()
+                .into_iter()
+                .flat_map(|address| address.iter())
+                .filter_map(|addr| addr.address.as_deref())
+                .any(|addr| addr.eq_ignore_ascii_case(self.sender.text()) || self.sender.is(addr))
+        }
+
+        fn search_criteria(&self) -> Option<SearchCriteria> {
+            // FROM would search From:, while filter checks Sender:
+            SearchCriteria::header("Sender", self.sender.search_text()?)
+        }
+    }
+
+    /// `From:` contains address
+    pub struct FromAddress {
+        pattern: Pattern,
+    }
+
+    impl FromAddress {
+        pub fn new(pattern: Pattern) -> Self {
+            Self { pattern }
+        }
+    }
+
+    impl Filter for FromAddress {
+        fn filter(&self, msg: &crate::OwnedMessage) -> bool {
+            let text = self.pattern.text().to_ascii_lowercase();
+
+            msg.from()
+                .into_iter()
+                .flat_map(|address| address.iter())
+                .filter_map(|addr| addr.address.as_deref())
+                .any(|addr| addr.to_ascii_lowercase().contains(&text) || self.pattern.is_in(addr))
+        }
+
+        fn search_criteria(&self) -> Option<SearchCriteria> {
+            SearchCriteria::from(self.pattern.search_text()?)
         }
     }
 }
 
-mod date {
-    use chrono::{DateTime, Utc};
+mod text {
+    use super::{search::SearchCriteria, Filter, Pattern};
+
+    /// Subject or any text part of body contains pattern
+    pub struct TextContains {
+        pattern: Pattern,
+    }
+
+    impl TextContains {
+        pub fn new(pattern: Pattern) -> Self {
+            Self { pattern }
+        }
+    }
+
+    impl Filter for TextContains {
+        fn filter(&self, msg: &crate::OwnedMessage) -> bool {
+            if msg
+                .subject()
+                .is_some_and(|subject| self.pattern.is_in(subject))
+            {
+                return true;
+            }
 
+            (0..msg.text_body_count())
+                .filter_map(|pos| msg.body_text(pos))
+                .any(|body| self.pattern.is_in(&body))
+        }
+
+        fn search_criteria(&self) -> Option<SearchCriteria> {
+            SearchCriteria::text(self.pattern.search_text()?)
+        }
+    }
+}
+
+mod spam {
     use super::Filter;
 
+    /// Message, marked as spam by SpamAssassin-like filter of server
+    pub struct Spam;
+
+    impl Filter for Spam {
+        fn filter(&self, msg: &crate::OwnedMessage) -> bool {
+            let has_yes = |header: &str| {
+                msg.header_raw(header).is_some_and(|value| {
+                    value
+                        .trim_start()
+                        .get(..3)
+                        .is_some_and(|yes| yes.eq_ignore_ascii_case("yes"))
+                })
+            };
+
+            has_yes("X-Spam-Flag") || has_yes("X-Spam-Status")
+        }
+    }
+}
+
+mod recipient {
+    use super::{search::SearchCriteria, Filter, Pattern};
+
+    /// To, Cc or Delivered-To contains address
+    pub struct Recipient {
+        recipient: Pattern,
+    }
+
+    impl Recipient {
+        pub fn new(recipient: Pattern) -> Self {
+            Self { recipient }
+        }
+    }
+
+    impl Filter for Recipient {
+        fn filter(&self, msg: &crate::OwnedMessage) -> bool {
+            let recipient = &self.recipient;
+
+            let in_address = [msg.to(), msg.cc()]
+                .into_iter()
+                .flatten()
+                .flat_map(|address| address.iter())
+                .filter_map(|addr| addr.address.as_deref())
+                .any(|addr| recipient.is_in(addr));
+
+            in_address
+                || msg.headers_raw().any(|(name, value)| {
+                    name.eq_ignore_ascii_case("Delivered-To") && recipient.is_in(value)
+                })
+        }
+
+        fn search_criteria(&self) -> Option<SearchCriteria> {
+            let recipient = self.recipient.search_text()?;
+            let to = SearchCriteria::to(recipient)?;
+            let cc = SearchCriteria::cc(recipient)?;
+            let delivered_to = SearchCriteria::header("Delivered-To", recipient)?;
+
+            Some(SearchCriteria::or(to, SearchCriteria::or(cc, delivered_to)))
+        }
+    }
+}
+
+mod header {
+    use super::{search::SearchCriteria, Filter, Pattern};
+
+    /// Raw value of header equals or contains value
+    pub struct Header {
+        name: String,
+        value: Pattern,
+        exact: bool,
+    }
+
+    impl Header {
+        pub fn equals(name: String, value: Pattern) -> Self {
+            Self {
+                name,
+                value,
+                exact: true,
+            }
+        }
+
+        pub fn contains(name: String, value: Pattern) -> Self {
+            Self {
+                name,
+                value,
+                exact: false,
+            }
+        }
+    }
+
+    impl Filter for Header {
+        fn filter(&self, msg: &crate::OwnedMessage) -> bool {
+            msg.headers_raw()
+                .filter(|(name, _)| name.eq_ignore_ascii_case(&self.name))
+                .any(|(_, value)| match self.exact {
+                    true => self.value.is(value.trim()),
+                    false => self.value.is_in(value),
+                })
+        }
+
+        fn search_criteria(&self) -> Option<SearchCriteria> {
+            SearchCriteria::header(&self.name, self.value.search_text()?)
+        }
+    }
+}
+
+mod body {
+    use mail_parser::MimeHeaders;
+
+    use super::{search::SearchCriteria, Filter, Pattern};
+
+    /// Any text/plain part of body contains pattern
+    pub struct BodyContains {
+        pattern: Pattern,
+    }
+
+    impl BodyContains {
+        pub fn new(pattern: Pattern) -> Self {
+            Self { pattern }
+        }
+    }
+
+    impl Filter for BodyContains {
+        fn filter(&self, msg: &crate::OwnedMessage) -> bool {
+            msg.text_bodies()
+                .filter(|part| {
+                    part.content_type().is_none() || part.is_content_type("text", "plain")
+                })
+                .filter_map(|part| part.text_contents())
+                .any(|text| self.pattern.is_in(text))
+        }
+
+        fn search_criteria(&self) -> Option<SearchCriteria> {
+            SearchCriteria::body(self.pattern.search_text()?)
+        }
+    }
+}
+
+mod attachment {
+    use mail_parser::MimeHeaders;
+
+    use super::{Filter, Pattern};
+
+    pub struct HasAttachment;
+
+    impl Filter for HasAttachment {
+        fn filter(&self, msg: &crate::OwnedMessage) -> bool {
+            msg.attachment_count() > 0
+        }
+    }
+
+    /// File name of any attachment contains pattern
+    pub struct AttachmentName {
+        pattern: Pattern,
+    }
+
+    impl AttachmentName {
+        pub fn new(pattern: Pattern) -> Self {
+            Self { pattern }
+        }
+    }
+
+    impl Filter for AttachmentName {
+        fn filter(&self, msg: &crate::OwnedMessage) -> bool {
+            msg.attachments()
+                .filter_map(|part| part.attachment_name())
+                .any(|name| self.pattern.is_in(name))
+        }
+    }
+
+    /// MIME type of any attachment is `type/subtype`, or `type/*` for any subtype
+    pub struct AttachmentType {
+        mime: String,
+    }
+
+    impl AttachmentType {
+        pub fn new(mime: String) -> Self {
+            Self { mime }
+        }
+    }
+
+    impl Filter for AttachmentType {
+        fn filter(&self, msg: &crate::OwnedMessage) -> bool {
+            let (c_type, subtype) = self.mime.split_once('/').unwrap_or((&self.mime, "*"));
+
+            msg.attachments()
+                .filter_map(|part| part.content_type())
+                .any(|content_type| {
+                    content_type.ctype().eq_ignore_ascii_case(c_type)
+                        && (subtype == "*"
+                            || content_type
+                                .subtype()
+                                .is_some_and(|sub| sub.eq_ignore_ascii_case(subtype)))
+                })
+        }
+    }
+}
+
+mod size {
+    use super::{search::SearchCriteria, Filter};
+
+    /// Size of raw message in bytes, strictly larger or smaller than given
+    pub enum Size {
+        Larger(u64),
+        Smaller(u64),
+    }
+
+    impl Size {
+        pub fn larger(bytes: u64) -> Self {
+            Self::Larger(bytes)
+        }
+
+        pub fn smaller(bytes: u64) -> Self {
+            Self::Smaller(bytes)
+        }
+    }
+
+    impl Filter for Size {
+        fn filter(&self, msg: &crate::OwnedMessage) -> bool {
+            let size = msg.raw_message().len() as u64;
+            match *self {
+                Size::Larger(bytes) => size > bytes,
+                Size::Smaller(bytes) => size < bytes,
+            }
+        }
+
+        fn search_criteria(&self) -> Option<SearchCriteria> {
+            Some(match *self {
+                Size::Larger(bytes) => SearchCriteria::Larger(bytes),
+                Size::Smaller(bytes) => SearchCriteria::Smaller(bytes),
+            })
+        }
+    }
+}
+
+pub mod matching {
+    use std::borrow::Cow;
+
+    use serde::{Deserialize, Serialize};
+    use unicode_normalization::{char::is_combining_mark, UnicodeNormalization};
+
+    /// Unicode normalization form, see [UAX #15](https://unicode.org/reports/tr15/)
+    #[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+    #[serde(rename_all = "snake_case")]
+    pub enum Normalization {
+        Nfc,
+        /// Also folds compatibility characters, e.g. fullwidth `Ａ` to `A`
+        Nfkc,
+    }
+
+    /// How text filters compare pattern with message. Default is exact comparison
+    #[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+    #[serde(default)]
+    pub struct MatchOptions {
+        /// Compare lowercased text
+        pub ignore_case: bool,
+        pub normalization: Option<Normalization>,
+        /// Trim text and collapse runs of whitespace into single space
+        pub collapse_whitespace: bool,
+        /// Strip diacritics, so `é` matches `e`
+        pub fold_diacritics: bool,
+    }
+
+    impl MatchOptions {
+        /// Every option enabled, with NFKC normalization
+        pub fn relaxed() -> Self {
+            Self {
+                ignore_case: true,
+                normalization: Some(Normalization::Nfkc),
+                collapse_whitespace: true,
+                fold_diacritics: true,
+            }
+        }
+
+        pub fn normalize<'a>(&self, text: &'a str) -> Cow<'a, str> {
+            let mut text = Cow::Borrowed(text);
+
+            if self.fold_diacritics {
+                let decomposed: String = match self.normalization {
+                    Some(Normalization::Nfkc) => text.nfkd().collect(),
+                    _ => text.nfd().collect(),
+                };
+                text = Cow::Owned(
+                    decomposed
+                        .chars()
+                        .filter(|&c| !is_combining_mark(c))
+                        .nfc()
+                        .collect(),
+                );
+            } else {
+                match self.normalization {
+                    Some(Normalization::Nfc) => text = Cow::Owned(text.nfc().collect()),
+                    Some(Normalization::Nfkc) => text = Cow::Owned(text.nfkc().collect()),
+                    None => {}
+                }
+            }
+
+            if self.ignore_case {
+                text = Cow::Owned(text.to_lowercase());
+            }
+            if self.collapse_whitespace {
+                text = Cow::Owned(text.split_whitespace().collect::<Vec<_>>().join(" "));
+            }
+
+            text
+        }
+
+        // IMAP SEARCH is case-insensitive substring match, other options can make it miss messages
+        fn is_server_safe(&self) -> bool {
+            self.normalization.is_none() && !self.collapse_whitespace && !self.fold_diacritics
+        }
+    }
+
+    /// Text, matched by filters with `MatchOptions`
+    ///
+    /// Plain strings convert into pattern with default, exact options
+    #[derive(Clone, Debug, PartialEq, Eq)]
+    pub struct Pattern {
+        text: String,
+        normalized: String,
+        options: MatchOptions,
+    }
+
+    impl Pattern {
+        pub fn new(text: impl Into<String>, options: MatchOptions) -> Self {
+            let text = text.into();
+            let normalized = options.normalize(&text).into_owned();
+
+            Self {
+                text,
+                normalized,
+                options,
+            }
+        }
+
+        pub fn text(&self) -> &str {
+            &self.text
+        }
+
+        pub fn options(&self) -> &MatchOptions {
+            &self.options
+        }
+
+        pub(crate) fn is(&self, text: &str) -> bool {
+            self.options.normalize(text) == self.normalized
+        }
+
+        pub(crate) fn is_in(&self, text: &str) -> bool {
+            self.options.normalize(text).contains(&self.normalized)
+        }
+
+        /// Text for IMAP SEARCH, `None` if server can't match with these options
+        pub(crate) fn search_text(&self) -> Option<&str> {
+            self.options.is_server_safe().then_some(self.text.as_str())
+        }
+    }
+
+    impl From<&str> for Pattern {
+        fn from(text: &str) -> Self {
+            Self::new(text, MatchOptions::default())
+        }
+    }
+
+    impl From<&String> for Pattern {
+        fn from(text: &String) -> Self {
+            Self::new(text.as_str(), MatchOptions::default())
+        }
+    }
+
+    impl From<String> for Pattern {
+        fn from(text: String) -> Self {
+            Self::new(text, MatchOptions::default())
+        }
+    }
+
+    impl From<Cow<'_, str>> for Pattern {
+        fn from(text: Cow<'_, str>) -> Self {
+            Self::new(text.into_owned(), MatchOptions::default())
+        }
+    }
+}
+
+mod date {
+    use chrono::{DateTime, Days, TimeDelta, Utc};
+    use mail_parser::HeaderName;
+
+    use super::{search::SearchCriteria, Filter};
+
     pub enum DateFilterMode {
         Since,
         Earlier,
     }
+
+    /// Header, from which date of message is taken
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub enum DateSource {
+        /// `Date:`, set by sender and may be arbitrary
+        Sent,
+        /// Topmost `Received:`, set by server of mailbox
+        Received,
+    }
+
+    enum Bound {
+        At(DateTime<Utc>),
+        /// Relative to the moment of filtering
+        Ago(TimeDelta),
+    }
+
     pub struct DateFilter {
         mode: DateFilterMode,
-        date: DateTime<Utc>,
+        bound: Bound,
+        source: DateSource,
     }
 
     impl DateFilter {
         pub fn since(date: DateTime<Utc>) -> Self {
             Self {
                 mode: DateFilterMode::Since,
-                date,
+                bound: Bound::At(date),
+                source: DateSource::Sent,
             }
         }
 
-        pub fn since_now() -> Self {
+        pub fn before(date: DateTime<Utc>) -> Self {
+            Self {
+                mode: DateFilterMode::Earlier,
+                bound: Bound::At(date),
+                source: DateSource::Sent,
+            }
+        }
+
+        /// Messages not older than `period` at the moment of filtering
+        pub fn within(period: TimeDelta) -> Self {
             Self {
                 mode: DateFilterMode::Since,
-                date: Utc::now(),
+                bound: Bound::Ago(period),
+                source: DateSource::Sent,
+            }
+        }
+
+        pub fn source(self, source: DateSource) -> Self {
+            Self { source, ..self }
+        }
+
+        fn date(&self) -> DateTime<Utc> {
+            match self.bound {
+                Bound::At(date) => date,
+                Bound::Ago(period) => Utc::now()
+                    .checked_sub_signed(period)
+                    .unwrap_or(DateTime::<Utc>::MIN_UTC),
             }
         }
     }
 
     impl Filter for DateFilter {
         fn filter(&self, msg: &crate::OwnedMessage) -> bool {
-            let Some(msg_date) = msg
-                .date()
-                .and_then(|x| DateTime::<Utc>::from_timestamp(x.to_timestamp(), 0))
-            else {
+            let msg_date = match self.source {
+                DateSource::Sent => crate::message::header_date(msg),
+                // `Message::received` returns the bottommost header, closest to sender
+                DateSource::Received => msg
+                    .headers()
+                    .iter()
+                    .find(|header| header.name == HeaderName::Received)
+                    .and_then(|header| header.value.as_received())
+                    .and_then(|received| received.date())
+                    .and_then(|date| DateTime::<Utc>::from_timestamp(date.to_timestamp(), 0)),
+            };
+            let Some(msg_date) = msg_date else {
                 return false;
             };
+
+            match self.mode {
+                DateFilterMode::Since => msg_date > self.date(),
+                DateFilterMode::Earlier => msg_date < self.date(),
+            }
+        }
+
+        // SENTSINCE/SENTBEFORE compare days of Date: header, ignoring time and timezone,
+        // so bounds are widened to never miss a message from another timezone.
+        // Received: has no criteria: SINCE/BEFORE use internal date, which differs for copied or imported messages
+        fn search_criteria(&self) -> Option<SearchCriteria> {
+            if self.source == DateSource::Received {
+                return None;
+            }
+
+            let day = self.date().date_naive();
             match self.mode {
-                DateFilterMode::Since => msg_date > self.date,
-                DateFilterMode::Earlier => msg_date < self.date,
+                DateFilterMode::Since => Some(SearchCriteria::SentSince(
+                    day.checked_sub_days(Days::new(1))?,
+                )),
+                DateFilterMode::Earlier => Some(SearchCriteria::SentBefore(
+                    day.checked_add_days(Days::new(2))?,
+                )),
+            }
+        }
+    }
+}
+pub mod search {
+    use std::fmt::{self, Display, Formatter};
+
+    use chrono::NaiveDate;
+
+    /// IMAP SEARCH criteria, see [RFC 3501](https://www.rfc-editor.org/rfc/rfc3501#section-6.4.4)
+    #[derive(Clone, Debug, PartialEq, Eq)]
+    pub enum SearchCriteria {
+        Subject(String),
+        From(String),
+        To(String),
+        Cc(String),
+        Header(String, String),
+        Body(String),
+        Text(String),
+        Larger(u64),
+        Smaller(u64),
+        SentSince(NaiveDate),
+        SentBefore(NaiveDate),
+        And(Box<SearchCriteria>, Box<SearchCriteria>),
+        Or(Box<SearchCriteria>, Box<SearchCriteria>),
+    }
+
+    impl SearchCriteria {
+        /// Messages containing `s` in Subject
+        ///
+        /// `None` if `s` can't be sent as quoted string
+        pub fn subject(s: &str) -> Option<Self> {
+            is_quotable(s).then(|| Self::Subject(s.to_owned()))
+        }
+
+        /// Messages containing `s` in From
+        ///
+        /// `None` if `s` can't be sent as quoted string
+        pub fn from(s: &str) -> Option<Self> {
+            is_quotable(s).then(|| Self::From(s.to_owned()))
+        }
+
+        /// Messages containing `s` in To
+        ///
+        /// `None` if `s` can't be sent as quoted string
+        pub fn to(s: &str) -> Option<Self> {
+            is_quotable(s).then(|| Self::To(s.to_owned()))
+        }
+
+        /// Messages containing `s` in Cc
+        ///
+        /// `None` if `s` can't be sent as quoted string
+        pub fn cc(s: &str) -> Option<Self> {
+            is_quotable(s).then(|| Self::Cc(s.to_owned()))
+        }
+
+        /// Messages containing `value` in header `name`
+        ///
+        /// `None` if `name` or `value` can't be sent as quoted string
+        pub fn header(name: &str, value: &str) -> Option<Self> {
+            (is_quotable(name) && is_quotable(value))
+                .then(|| Self::Header(name.to_owned(), value.to_owned()))
+        }
+
+        /// Messages containing `s` in body
+        ///
+        /// `None` if `s` can't be sent as quoted string
+        pub fn body(s: &str) -> Option<Self> {
+            is_quotable(s).then(|| Self::Body(s.to_owned()))
+        }
+
+        /// Messages containing `s` in headers or body
+        ///
+        /// `None` if `s` can't be sent as quoted string
+        pub fn text(s: &str) -> Option<Self> {
+            is_quotable(s).then(|| Self::Text(s.to_owned()))
+        }
+
+        pub fn and(first: Self, second: Self) -> Self {
+            Self::And(Box::new(first), Box::new(second))
+        }
+
+        pub fn or(first: Self, second: Self) -> Self {
+            Self::Or(Box::new(first), Box::new(second))
+        }
+    }
+
+    // Non-ASCII strings need CHARSET and literals, which aren't worth it: local filter handles them
+    fn is_quotable(s: &str) -> bool {
+        s.chars().all(|c| c.is_ascii() && !c.is_ascii_control())
+    }
+
+    struct Quoted<'a>(&'a str);
+
+    impl Display for Quoted<'_> {
+        fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+            write!(f, "\"")?;
+            for c in self.0.chars() {
+                if c == '"' || c == '\\' {
+                    write!(f, "\\")?;
+                }
+                write!(f, "{c}")?;
             }
+            write!(f, "\"")
+        }
+    }
+
+    struct Day(NaiveDate);
+
+    impl Display for Day {
+        fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+            write!(f, "{}", self.0.format("%-d-%b-%Y"))
+        }
+    }
+
+    impl Display for SearchCriteria {
+        fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+            match self {
+                Self::Subject(s) => write!(f, "SUBJECT {}", Quoted(s)),
+                Self::From(s) => write!(f, "FROM {}", Quoted(s)),
+                Self::To(s) => write!(f, "TO {}", Quoted(s)),
+                Self::Cc(s) => write!(f, "CC {}", Quoted(s)),
+                Self::Header(name, value) => {
+                    write!(f, "HEADER {} {}", Quoted(name), Quoted(value))
+                }
+                Self::Body(s) => write!(f, "BODY {}", Quoted(s)),
+                Self::Text(s) => write!(f, "TEXT {}", Quoted(s)),
+                Self::Larger(bytes) => write!(f, "LARGER {bytes}"),
+                Self::Smaller(bytes) => write!(f, "SMALLER {bytes}"),
+                Self::SentSince(day) => write!(f, "SENTSINCE {}", Day(*day)),
+                Self::SentBefore(day) => write!(f, "SENTBEFORE {}", Day(*day)),
+                Self::And(first, second) => write!(f, "{first} {second}"),
+                Self::Or(first, second) => write!(f, "OR ({first}) ({second})"),
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use chrono::{TimeZone, Utc};
+
+        use super::*;
+        use crate::filters::{Filter, FilterExt, Filters};
+
+        #[test]
+        fn test_display() {
+            assert_eq!(
+                SearchCriteria::subject(r#"say "hi" \ bye"#)
+                    .unwrap()
+                    .to_string(),
+                r#"SUBJECT "say \"hi\" \\ bye""#
+            );
+            assert_eq!(SearchCriteria::subject("код"), None);
+            assert_eq!(SearchCriteria::subject("a\r\nb"), None);
+
+            let criteria = SearchCriteria::or(
+                SearchCriteria::and(
+                    SearchCriteria::From("a".to_owned()),
+                    SearchCriteria::Subject("b".to_owned()),
+                ),
+                SearchCriteria::or(SearchCriteria::Larger(10), SearchCriteria::Smaller(5)),
+            );
+            assert_eq!(
+                criteria.to_string(),
+                r#"OR (FROM "a" SUBJECT "b") (OR (LARGER 10) (SMALLER 5))"#
+            );
+        }
+
+        #[test]
+        fn test_date_widening() {
+            let date = Utc.with_ymd_and_hms(2024, 5, 1, 23, 30, 0).unwrap();
+
+            assert_eq!(
+                Filters::sent_since(date)
+                    .search_criteria()
+                    .unwrap()
+                    .to_string(),
+                "SENTSINCE 30-Apr-2024"
+            );
+            assert_eq!(
+                Filters::sent_before(date)
+                    .search_criteria()
+                    .unwrap()
+                    .to_string(),
+                "SENTBEFORE 3-May-2024"
+            );
+            assert_eq!(
+                Filters::sent_between(date, date)
+                    .search_criteria()
+                    .unwrap()
+                    .to_string(),
+                "SENTSINCE 30-Apr-2024 SENTBEFORE 3-May-2024"
+            );
+            assert!(Filters::sent_since(date)
+                .or_subject_contains("код")
+                .search_criteria()
+                .is_none());
         }
     }
 }
+
 #[cfg(feature = "regex")]
 pub mod regex {
     use std::borrow::Cow;
@@ -286,7 +1185,6 @@ pub mod regex {
 
     impl Filter for RegexFilter {
         fn filter(&self, msg: &OwnedMessage) -> bool {
-            let msg = msg.borrow_dependent();
             let empty = Cow::Owned(String::new());
             let body = msg.body_html(0).unwrap_or(empty);
 
@@ -294,3 +1192,876 @@ pub mod regex {
         }
     }
 }
+
+pub mod spec {
+    use std::time::Duration;
+
+    use chrono::{DateTime, TimeDelta, Utc};
+    use serde::{Deserialize, Serialize};
+    use serde_with::{serde_as, DurationSeconds};
+
+    use super::{
+        attachment::{AttachmentName, AttachmentType, HasAttachment},
+        body::BodyContains,
+        date::{DateFilter, DateSource},
+        header::Header,
+        logical::{And, Not, Or},
+        recipient::Recipient,
+        sender::{FromAddress, Sender},
+        size::Size,
+        spam::Spam,
+        subject::{Subject, SubjectContains},
+        text::TextContains,
+        Filter, MatchOptions, Pattern,
+    };
+
+    /// Serializable description of filter, e.g. from JSON config
+    ///
+    /// ```json
+    /// {"and": [{"sender": "noreply@example.com"}, {"not": {"subject_contains": "digest"}}]}
+    /// ```
+    #[serde_as]
+    #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+    #[serde(rename_all = "snake_case")]
+    pub enum FilterSpec {
+        Subject(String),
+        SubjectContains(String),
+        Sender(String),
+        /// Address of `From:` contains the string
+        From(String),
+        SentSince(DateTime<Utc>),
+        SentBefore(DateTime<Utc>),
+        /// Dated by topmost `Received:` header
+        ReceivedSince(DateTime<Utc>),
+        /// Dated by topmost `Received:` header
+        ReceivedBefore(DateTime<Utc>),
+        /// Sent during the last seconds before filtering, see `Filters::sent_within`
+        SentWithin(#[serde_as(as = "DurationSeconds<u64>")] Duration),
+        /// Received during the last seconds before filtering, see `Filters::received_within`
+        ReceivedWithin(#[serde_as(as = "DurationSeconds<u64>")] Duration),
+        /// Subject or text body contains the string
+        TextContains(String),
+        /// Marked as spam by server, see `Filters::spam`
+        Spam,
+        /// To, Cc or Delivered-To contains the address
+        Recipient(String),
+        Header {
+            name: String,
+            value: String,
+        },
+        HeaderContains {
+            name: String,
+            value: String,
+        },
+        /// Text/plain part of body contains the string
+        BodyContains(String),
+        HasAttachment,
+        /// File name of attachment contains the string
+        AttachmentName(String),
+        /// MIME type of attachment, e.g. `application/pdf` or `image/*`
+        AttachmentType(String),
+        /// Larger than given bytes
+        Larger(u64),
+        /// Smaller than given bytes
+        Smaller(u64),
+        /// Pattern, matched against HTML body. Requires `regex` feature
+        Regex(String),
+        And(Vec<FilterSpec>),
+        Or(Vec<FilterSpec>),
+        Not(Box<FilterSpec>),
+        /// Text filters inside `filter` compare with `options` instead of exactly
+        ///
+        /// ```json
+        /// {"matching": {"options": {"ignore_case": true}, "filter": {"subject_contains": "code"}}}
+        /// ```
+        Matching {
+            options: MatchOptions,
+            filter: Box<FilterSpec>,
+        },
+    }
+
+    /// Invalid node of `FilterSpec`
+    #[derive(thiserror::Error, Clone, Debug, PartialEq, Eq)]
+    #[error("invalid filter at {path}: {reason}")]
+    pub struct FilterSpecError {
+        /// Path of node from the root, e.g. `$.and[1].not.regex`
+        pub path: String,
+        pub reason: String,
+    }
+
+    impl FilterSpec {
+        /// Validates spec and builds filter out of it
+        pub fn compile(&self) -> Result<Box<dyn Filter>, FilterSpecError> {
+            self.compile_at("$".to_owned(), &MatchOptions::default())
+        }
+
+        fn compile_at(
+            &self,
+            path: String,
+            options: &MatchOptions,
+        ) -> Result<Box<dyn Filter>, FilterSpecError> {
+            let error = |path: String, reason: &str| FilterSpecError {
+                path,
+                reason: reason.to_owned(),
+            };
+            let pattern = |text: &String| Pattern::new(text.as_str(), options.clone());
+
+            Ok(match self {
+                FilterSpec::Subject(subject) => Box::new(Subject::new(pattern(subject))),
+                FilterSpec::SubjectContains(text) => Box::new(SubjectContains::new(pattern(text))),
+                FilterSpec::Sender(sender) => Box::new(Sender::new(pattern(sender))),
+                FilterSpec::From(from) => Box::new(FromAddress::new(pattern(from))),
+                FilterSpec::SentSince(date) => Box::new(DateFilter::since(*date)),
+                FilterSpec::SentBefore(date) => Box::new(DateFilter::before(*date)),
+                FilterSpec::ReceivedSince(date) => {
+                    Box::new(DateFilter::since(*date).source(DateSource::Received))
+                }
+                FilterSpec::ReceivedBefore(date) => {
+                    Box::new(DateFilter::before(*date).source(DateSource::Received))
+                }
+                FilterSpec::SentWithin(period) | FilterSpec::ReceivedWithin(period) => {
+                    let (name, source) = match self {
+                        FilterSpec::SentWithin(_) => ("sent_within", DateSource::Sent),
+                        _ => ("received_within", DateSource::Received),
+                    };
+                    let period = TimeDelta::from_std(*period)
+                        .map_err(|_| error(format!("{path}.{name}"), "period is too long"))?;
+
+                    Box::new(DateFilter::within(period).source(source))
+                }
+                FilterSpec::TextContains(text) => Box::new(TextContains::new(pattern(text))),
+                FilterSpec::Spam => Box::new(Spam),
+                FilterSpec::Recipient(recipient) => Box::new(Recipient::new(pattern(recipient))),
+                FilterSpec::Header { name, value } => {
+                    Box::new(Header::equals(name.clone(), pattern(value)))
+                }
+                FilterSpec::HeaderContains { name, value } => {
+                    Box::new(Header::contains(name.clone(), pattern(value)))
+                }
+                FilterSpec::BodyContains(text) => Box::new(BodyContains::new(pattern(text))),
+                FilterSpec::HasAttachment => Box::new(HasAttachment),
+                FilterSpec::AttachmentName(text) => Box::new(AttachmentName::new(pattern(text))),
+                FilterSpec::AttachmentType(mime) => Box::new(AttachmentType::new(mime.clone())),
+                FilterSpec::Larger(bytes) => Box::new(Size::larger(*bytes)),
+                FilterSpec::Smaller(bytes) => Box::new(Size::smaller(*bytes)),
+                FilterSpec::Regex(pattern) => compile_regex(pattern)
+                    .map_err(|reason| error(format!("{path}.regex"), &reason))?,
+                FilterSpec::And(specs) | FilterSpec::Or(specs) => {
+                    let is_and = matches!(self, FilterSpec::And(_));
+                    let name = if is_and { "and" } else { "or" };
+
+                    let mut filters = specs.iter().enumerate().map(|(index, spec)| {
+                        spec.compile_at(format!("{path}.{name}[{index}]"), options)
+                    });
+
+                    let Some(first) = filters.next() else {
+                        return Err(error(format!("{path}.{name}"), "needs at least one filter"));
+                    };
+
+                    filters.try_fold(first?, |joined, filter| {
+                        let filter = filter?;
+                        Ok(match is_and {
+                            true => Box::new(And::new(joined, filter)) as Box<dyn Filter>,
+                            false => Box::new(Or::new(joined, filter)),
+                        })
+                    })?
+                }
+                FilterSpec::Not(spec) => {
+                    Box::new(Not::new(spec.compile_at(format!("{path}.not"), options)?))
+                }
+                FilterSpec::Matching { options, filter } => {
+                    filter.compile_at(format!("{path}.matching.filter"), options)?
+                }
+            })
+        }
+    }
+
+    #[cfg(feature = "regex")]
+    fn compile_regex(pattern: &str) -> Result<Box<dyn Filter>, String> {
+        let regex = regex::Regex::new(pattern).map_err(|err| err.to_string())?;
+        Ok(Box::new(super::regex::RegexFilter::new(regex)))
+    }
+
+    #[cfg(not(feature = "regex"))]
+    fn compile_regex(_: &str) -> Result<Box<dyn Filter>, String> {
+        Err("regex filters need `regex` feature of getemail".to_owned())
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn test_compile() {
+            let spec: FilterSpec = serde_json::from_str(
+                r#"{"and": [{"sender": "noreply@example.com"}, {"not": {"subject_contains": "digest"}}]}"#,
+            )
+            .unwrap();
+            assert!(spec.compile().is_ok());
+
+            let spec: FilterSpec =
+                serde_json::from_str(r#"{"or": [{"subject": "a"}, {"not": {"and": []}}]}"#)
+                    .unwrap();
+            let err = spec.compile().err().unwrap();
+            assert_eq!(err.path, "$.or[1].not.and");
+
+            let spec: FilterSpec = serde_json::from_str(
+                r#"{"matching": {"options": {"ignore_case": true}, "filter": {"subject_contains": "code"}}}"#,
+            )
+            .unwrap();
+            assert_eq!(
+                spec.compile()
+                    .unwrap()
+                    .search_criteria()
+                    .unwrap()
+                    .to_string(),
+                r#"SUBJECT "code""#
+            );
+
+            let spec: FilterSpec =
+                serde_json::from_str(r#"{"or": [{"sent_within": 600}, {"received_within": 600}]}"#)
+                    .unwrap();
+            assert_eq!(
+                spec,
+                FilterSpec::Or(vec![
+                    FilterSpec::SentWithin(Duration::from_secs(600)),
+                    FilterSpec::ReceivedWithin(Duration::from_secs(600)),
+                ])
+            );
+            assert!(spec.compile().is_ok());
+        }
+    }
+}
+
+pub mod query {
+    use std::{ops::Range, str::FromStr, time::Duration};
+
+    use chrono::{NaiveDate, TimeDelta};
+
+    use super::{spec::FilterSpec, Filter};
+
+    /// Gmail-style query, e.g. `from:noreply@x.com subject:"verify" after:2024-05-01 -is:spam (code OR token)`
+    ///
+    /// Terms are joined with AND, unless separated by `OR`. `-` negates the next term, parentheses group.
+    /// Supported terms:
+    /// - `word` or `"quoted phrase"`: subject or text body contains it.
+    ///   Words with colon, which don't start with a field below, are text too, e.g. `10:30`
+    /// - `from:address`: address of `From:` contains the address
+    /// - `to:address`: To, Cc or Delivered-To contains the address
+    /// - `subject:text`: subject contains the text
+    /// - `after:YYYY-MM-DD` (or `since:`), `before:YYYY-MM-DD`: sent since or before the date
+    /// - `newer_than:period`: sent during the last period, e.g. `newer_than:10min`.
+    ///   Units are `min`, `h`, `d`, `w`, `m` (30 days) and `y` (365 days)
+    /// - `is:spam`: marked as spam by server
+    /// - `has:attachment`, `filename:name`: any attachment, attachment with name
+    /// - `larger:size`, `smaller:size`: size in bytes, `K` or `M` suffix allowed, e.g. `larger:5M`
+    #[derive(Clone, Debug, PartialEq, Eq)]
+    pub struct Query {
+        pub spec: FilterSpec,
+        /// Terms of query in order of appearance
+        pub terms: Vec<QueryTerm>,
+    }
+
+    /// Single term of query, e.g. `from:noreply@x.com`
+    #[derive(Clone, Debug, PartialEq, Eq)]
+    pub struct QueryTerm {
+        /// Byte range of term in query
+        pub span: Range<usize>,
+        pub spec: FilterSpec,
+        /// Term alone can be translated into IMAP SEARCH, so server does the filtering
+        pub server_side: bool,
+    }
+
+    /// Query couldn't be parsed
+    #[derive(thiserror::Error, Clone, Debug, PartialEq, Eq)]
+    #[error("{reason} at position {position}")]
+    pub struct QueryError {
+        /// Byte offset in query
+        pub position: usize,
+        pub reason: String,
+    }
+
+    impl Query {
+        pub fn parse(query: &str) -> Result<Self, QueryError> {
+            let tokens = lex(query)?;
+            let mut parser = Parser {
+                tokens,
+                next: 0,
+                end: query.len(),
+                terms: Vec::new(),
+            };
+
+            if parser.tokens.is_empty() {
+                return Err(error(0, "empty query"));
+            }
+
+            let spec = parser.or_expr()?;
+            if let Some((_, span)) = parser.peek() {
+                return Err(error(span.start, "unexpected `)`"));
+            }
+
+            Ok(Self {
+                spec,
+                terms: parser.terms,
+            })
+        }
+
+        pub fn compile(&self) -> Box<dyn Filter> {
+            self.spec
+                .compile()
+                .expect("query produces only valid filter specs")
+        }
+    }
+
+    impl FromStr for Query {
+        type Err = QueryError;
+
+        fn from_str(query: &str) -> Result<Self, Self::Err> {
+            Self::parse(query)
+        }
+    }
+
+    fn error(position: usize, reason: impl Into<String>) -> QueryError {
+        QueryError {
+            position,
+            reason: reason.into(),
+        }
+    }
+
+    #[derive(Clone, Debug, PartialEq, Eq)]
+    enum Token {
+        Open,
+        Close,
+        Minus,
+        Or,
+        And,
+        Word(String),
+        Field {
+            name: String,
+            value: String,
+            value_start: usize,
+        },
+    }
+
+    fn is_word_end(c: char) -> bool {
+        c.is_whitespace() || matches!(c, '(' | ')' | '"')
+    }
+
+    fn lex(query: &str) -> Result<Vec<(Token, Range<usize>)>, QueryError> {
+        let mut tokens = Vec::new();
+        let mut chars = query.char_indices().peekable();
+
+        while let Some(&(start, c)) = chars.peek() {
+            let token = match c {
+                c if c.is_whitespace() => {
+                    chars.next();
+                    continue;
+                }
+                '(' | ')' | '-' => {
+                    chars.next();
+                    match c {
+                        '(' => Token::Open,
+                        ')' => Token::Close,
+                        _ => Token::Minus,
+                    }
+                }
+                '"' => Token::Word(lex_quoted(&mut chars)?),
+                _ => {
+                    let name = lex_word(query, &mut chars, |c| is_word_end(c) || c == ':');
+                    let is_field = FIELDS.contains(&name.as_str())
+                        && chars.peek().is_some_and(|&(_, c)| c == ':');
+
+                    if is_field {
+                        chars.next();
+                        let value_start = chars.peek().map_or(query.len(), |&(at, _)| at);
+                        let value = match chars.peek() {
+                            Some((_, '"')) => lex_quoted(&mut chars)?,
+                            _ => lex_word(query, &mut chars, is_word_end),
+                        };
+                        if value.is_empty() {
+                            return Err(error(value_start, format!("missing value of `{name}:`")));
+                        }
+
+                        Token::Field {
+                            name,
+                            value,
+                            value_start,
+                        }
+                    } else {
+                        // Colon of anything else, e.g. `10:30` or URL, is part of the word
+                        lex_word(query, &mut chars, is_word_end);
+                        let end = chars.peek().map_or(query.len(), |&(at, _)| at);
+
+                        match &query[start..end] {
+                            "OR" => Token::Or,
+                            "AND" => Token::And,
+                            word => Token::Word(word.to_owned()),
+                        }
+                    }
+                }
+            };
+
+            let end = chars.peek().map_or(query.len(), |&(at, _)| at);
+            tokens.push((token, start..end));
+        }
+
+        Ok(tokens)
+    }
+
+    fn lex_word(
+        query: &str,
+        chars: &mut std::iter::Peekable<std::str::CharIndices>,
+        is_end: impl Fn(char) -> bool,
+    ) -> String {
+        let start = chars.peek().map_or(query.len(), |&(at, _)| at);
+        while chars.next_if(|&(_, c)| !is_end(c)).is_some() {}
+        let end = chars.peek().map_or(query.len(), |&(at, _)| at);
+
+        query[start..end].to_owned()
+    }
+
+    fn lex_quoted(
+        chars: &mut std::iter::Peekable<std::str::CharIndices>,
+    ) -> Result<String, QueryError> {
+        let (start, _) = chars.next().expect("caller peeked opening quote");
+        let mut value = String::new();
+
+        loop {
+            match chars.next() {
+                Some((_, '"')) => return Ok(value),
+                Some((_, '\\')) => match chars.next() {
+                    Some((_, c)) => value.push(c),
+                    None => break,
+                },
+                Some((_, c)) => value.push(c),
+                None => break,
+            }
+        }
+
+        Err(error(start, "unterminated quote"))
+    }
+
+    struct Parser {
+        tokens: Vec<(Token, Range<usize>)>,
+        next: usize,
+        /// Length of query, position of errors at its end
+        end: usize,
+        terms: Vec<QueryTerm>,
+    }
+
+    impl Parser {
+        fn peek(&self) -> Option<&(Token, Range<usize>)> {
+            self.tokens.get(self.next)
+        }
+
+        fn advance(&mut self) -> Option<(Token, Range<usize>)> {
+            let token = self.tokens.get(self.next).cloned();
+            self.next += 1;
+            token
+        }
+
+        fn or_expr(&mut self) -> Result<FilterSpec, QueryError> {
+            let mut specs = vec![self.and_expr()?];
+            while matches!(self.peek(), Some((Token::Or, _))) {
+                self.advance();
+                specs.push(self.and_expr()?);
+            }
+
+            Ok(match specs.len() {
+                1 => specs.remove(0),
+                _ => FilterSpec::Or(specs),
+            })
+        }
+
+        fn and_expr(&mut self) -> Result<FilterSpec, QueryError> {
+            let mut specs = vec![self.unary()?];
+            loop {
+                match self.peek() {
+                    None | Some((Token::Or | Token::Close, _)) => break,
+                    Some((Token::And, _)) => {
+                        self.advance();
+                    }
+                    Some(_) => {}
+                }
+                specs.push(self.unary()?);
+            }
+
+            Ok(match specs.len() {
+                1 => specs.remove(0),
+                _ => FilterSpec::And(specs),
+            })
+        }
+
+        fn unary(&mut self) -> Result<FilterSpec, QueryError> {
+            let Some((token, span)) = self.advance() else {
+                return Err(error(self.end, "expected term"));
+            };
+
+            match token {
+                Token::Minus => Ok(FilterSpec::Not(Box::new(self.unary()?))),
+                Token::Open => {
+                    let spec = self.or_expr()?;
+                    match self.advance() {
+                        Some((Token::Close, _)) => Ok(spec),
+                        _ => Err(error(span.start, "unclosed `(`")),
+                    }
+                }
+                Token::Close => Err(error(span.start, "unexpected `)`")),
+                Token::Or => Err(error(span.start, "unexpected `OR`")),
+                Token::And => Err(error(span.start, "unexpected `AND`")),
+                Token::Word(word) => Ok(self.term(FilterSpec::TextContains(word), span)),
+                Token::Field {
+                    name,
+                    value,
+                    value_start,
+                } => {
+                    let spec = field_spec(&name, value, value_start)?;
+                    Ok(self.term(spec, span))
+                }
+            }
+        }
+
+        fn term(&mut self, spec: FilterSpec, span: Range<usize>) -> FilterSpec {
+            let server_side = spec
+                .compile()
+                .is_ok_and(|filter| filter.search_criteria().is_some());
+
+            self.terms.push(QueryTerm {
+                span,
+                spec: spec.clone(),
+                server_side,
+            });
+            spec
+        }
+    }
+
+    /// Names of `field:value` terms, colon of other words is a part of text
+    const FIELDS: &[&str] = &[
+        "from",
+        "to",
+        "subject",
+        "filename",
+        "larger",
+        "smaller",
+        "after",
+        "since",
+        "before",
+        "newer_than",
+        "has",
+        "is",
+    ];
+
+    /// Spec of field, which is one of `FIELDS`
+    fn field_spec(name: &str, value: String, value_start: usize) -> Result<FilterSpec, QueryError> {
+        Ok(match name {
+            "from" => FilterSpec::From(value),
+            "to" => FilterSpec::Recipient(value),
+            "subject" => FilterSpec::SubjectContains(value),
+            "filename" => FilterSpec::AttachmentName(value),
+            "larger" | "smaller" => {
+                let Some(bytes) = parse_size(&value) else {
+                    return Err(error(
+                        value_start,
+                        format!("invalid size `{value}`, expected bytes with optional K or M"),
+                    ));
+                };
+
+                match name {
+                    "larger" => FilterSpec::Larger(bytes),
+                    _ => FilterSpec::Smaller(bytes),
+                }
+            }
+            "after" | "since" | "before" => {
+                let date = NaiveDate::parse_from_str(&value, "%Y-%m-%d")
+                    .or_else(|_| NaiveDate::parse_from_str(&value, "%Y/%m/%d"));
+                let Ok(date) = date else {
+                    return Err(error(
+                        value_start,
+                        format!("invalid date `{value}`, expected YYYY-MM-DD"),
+                    ));
+                };
+
+                let date = date.and_time(Default::default()).and_utc();
+                match name {
+                    "before" => FilterSpec::SentBefore(date),
+                    _ => FilterSpec::SentSince(date),
+                }
+            }
+            "newer_than" => {
+                let Some(period) = parse_period(&value) else {
+                    return Err(error(
+                        value_start,
+                        format!("invalid period `{value}`, expected e.g. 10min, 2h or 7d"),
+                    ));
+                };
+                // Filter specs hold periods as `TimeDelta`, which is shorter than `Duration`
+                if TimeDelta::from_std(period).is_err() {
+                    return Err(error(value_start, format!("period `{value}` is too long")));
+                }
+
+                FilterSpec::SentWithin(period)
+            }
+            "has" => match value.as_str() {
+                "attachment" => FilterSpec::HasAttachment,
+                _ => {
+                    return Err(error(
+                        value_start,
+                        format!("unknown value of `has:`: `{value}`"),
+                    ))
+                }
+            },
+            "is" => match value.as_str() {
+                "spam" => FilterSpec::Spam,
+                _ => {
+                    return Err(error(
+                        value_start,
+                        format!("unknown value of `is:`: `{value}`"),
+                    ))
+                }
+            },
+            _ => unreachable!("lexer produces only known fields, got `{name}:`"),
+        })
+    }
+
+    fn parse_period(value: &str) -> Option<Duration> {
+        let unit_start = value.find(|c: char| !c.is_ascii_digit())?;
+        let (count, unit) = value.split_at(unit_start);
+
+        let unit_secs = match unit {
+            "min" => 60,
+            "h" => 60 * 60,
+            "d" => 24 * 60 * 60,
+            "w" => 7 * 24 * 60 * 60,
+            "m" => 30 * 24 * 60 * 60,
+            "y" => 365 * 24 * 60 * 60,
+            _ => return None,
+        };
+
+        Some(Duration::from_secs(
+            count.parse::<u64>().ok()?.checked_mul(unit_secs)?,
+        ))
+    }
+
+    fn parse_size(value: &str) -> Option<u64> {
+        let (digits, multiplier) = match value.as_bytes().last()? {
+            b'k' | b'K' => (&value[..value.len() - 1], 1024),
+            b'm' | b'M' => (&value[..value.len() - 1], 1024 * 1024),
+            _ => (value, 1),
+        };
+
+        digits.parse::<u64>().ok()?.checked_mul(multiplier)
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use chrono::{TimeZone, Utc};
+
+        use super::*;
+
+        #[test]
+        fn test_parse() {
+            let query = Query::parse(
+                r#"from:noreply@x.com subject:"verify" after:2024-05-01 -is:spam (code OR token)"#,
+            )
+            .unwrap();
+
+            assert_eq!(
+                query.spec,
+                FilterSpec::And(vec![
+                    FilterSpec::From("noreply@x.com".to_owned()),
+                    FilterSpec::SubjectContains("verify".to_owned()),
+                    FilterSpec::SentSince(Utc.with_ymd_and_hms(2024, 5, 1, 0, 0, 0).unwrap()),
+                    FilterSpec::Not(Box::new(FilterSpec::Spam)),
+                    FilterSpec::Or(vec![
+                        FilterSpec::TextContains("code".to_owned()),
+                        FilterSpec::TextContains("token".to_owned()),
+                    ]),
+                ])
+            );
+
+            let spans: Vec<_> = query.terms.iter().map(|term| term.span.clone()).collect();
+            assert_eq!(spans, [0..18, 19..35, 36..52, 54..61, 63..67, 71..76]);
+
+            let server_side: Vec<_> = query.terms.iter().map(|term| term.server_side).collect();
+            assert_eq!(server_side, [true, true, true, false, true, true]);
+
+            let raw = b"From: No Reply <NoReply@X.com>\r\n\
+                Subject: Please verify\r\n\
+                Date: Sat, 1 Jun 2024 10:00:00 +0000\r\n\r\n\
+                Your code is 1234\r\n";
+            let msg = mail_parser::MessageParser::new()
+                .parse(&raw[..])
+                .unwrap()
+                .into_owned();
+            assert!(query.compile().filter(&msg));
+
+            assert_eq!(
+                Query::parse("newer_than:10min").unwrap().spec,
+                FilterSpec::SentWithin(Duration::from_secs(600))
+            );
+
+            // Words with colon, which aren't fields, are text
+            assert_eq!(
+                Query::parse("at 10:30 https://x.com/a cc:b").unwrap().spec,
+                FilterSpec::And(vec![
+                    FilterSpec::TextContains("at".to_owned()),
+                    FilterSpec::TextContains("10:30".to_owned()),
+                    FilterSpec::TextContains("https://x.com/a".to_owned()),
+                    FilterSpec::TextContains("cc:b".to_owned()),
+                ])
+            );
+        }
+
+        #[test]
+        fn test_parse_errors() {
+            let position = |query: &str| Query::parse(query).unwrap_err().position;
+
+            assert_eq!(position(""), 0);
+            assert_eq!(position("subject:\"verify"), 8);
+            assert_eq!(position("code OR"), 7);
+            assert_eq!(position("a (b OR c"), 2);
+            assert_eq!(position("a b)"), 3);
+            assert_eq!(position("larger:5G"), 7);
+            assert_eq!(position("after:2024-13-01"), 6);
+            assert_eq!(position("is:unread"), 3);
+            assert_eq!(position("from: x"), 5);
+            assert_eq!(position("newer_than:10s"), 11);
+            assert_eq!(position("newer_than:300000000y"), 11);
+            assert_eq!(position("newer_than:99999999999999999999d"), 11);
+
+            // The longest accepted period still compiles
+            let msg = mail_parser::MessageParser::new()
+                .parse(&b"Subject: x\r\n\r\n"[..])
+                .unwrap()
+                .into_owned();
+            assert!(!Query::parse("newer_than:290000000y")
+                .unwrap()
+                .compile()
+                .filter(&msg));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mail_parser::MessageParser;
+
+    use super::*;
+
+    #[test]
+    fn test_message_predicates() {
+        let raw = b"From: noreply@example.com\r\n\
+            To: user+shop@example.com\r\n\
+            Delivered-To: catchall@example.org\r\n\
+            X-Mailer: Mailer 1.0\r\n\
+            Subject: Invoice\r\n\
+            Content-Type: multipart/mixed; boundary=\"b\"\r\n\r\n\
+            --b\r\n\
+            Content-Type: text/plain\r\n\r\n\
+            Your code is 1234\r\n\
+            --b\r\n\
+            Content-Type: application/pdf\r\n\
+            Content-Disposition: attachment; filename=\"invoice.pdf\"\r\n\r\n\
+            %PDF\r\n\
+            --b--\r\n";
+        let msg = MessageParser::new().parse(&raw[..]).unwrap().into_owned();
+
+        assert!(Filters::recipient("+shop@").filter(&msg));
+        assert!(Filters::recipient("catchall@").filter(&msg));
+        assert!(!Filters::recipient("other@").filter(&msg));
+        assert!(Filters::header("x-mailer", "Mailer 1.0").filter(&msg));
+        assert!(!Filters::header("X-Mailer", "Mailer").filter(&msg));
+        assert!(Filters::header_contains("X-Mailer", "Mailer").filter(&msg));
+        assert!(Filters::body_contains("code is").filter(&msg));
+        assert!(Filters::has_attachment().filter(&msg));
+        assert!(Filters::attachment_name(".pdf").filter(&msg));
+        assert!(Filters::attachment_type("application/*").filter(&msg));
+        assert!(!Filters::attachment_type("image/png").filter(&msg));
+        assert!(Filters::larger(100).and_smaller(10_000).filter(&msg));
+
+        assert_eq!(
+            Filters::recipient("a@b.c")
+                .search_criteria()
+                .unwrap()
+                .to_string(),
+            r#"OR (TO "a@b.c") (OR (CC "a@b.c") (HEADER "Delivered-To" "a@b.c"))"#
+        );
+    }
+
+    #[test]
+    fn test_match_options() {
+        let raw = "From: noreply@example.com\r\n\
+            Sender: NoReply@Example.com\r\n\
+            Subject: =?utf-8?q?Co=CC=81digo_de___verificaci=C3=B3n?=\r\n\r\n\
+            Body\r\n";
+        let msg = MessageParser::new()
+            .parse(raw.as_bytes())
+            .unwrap()
+            .into_owned();
+
+        assert!(!Filters::subject_contains("codigo de verificacion").filter(&msg));
+        assert!(Filters::sender("noreply@example.com").filter(&msg));
+        assert_eq!(
+            Filters::sender("noreply@example.com")
+                .search_criteria()
+                .unwrap()
+                .to_string(),
+            r#"HEADER "Sender" "noreply@example.com""#
+        );
+
+        let relaxed = |text| Pattern::new(text, MatchOptions::relaxed());
+        assert!(Filters::subject_contains(relaxed("CODIGO DE VERIFICACION")).filter(&msg));
+        assert!(Filters::subject(relaxed(" código  de verificación ")).filter(&msg));
+        assert!(Filters::subject_contains(relaxed("code"))
+            .search_criteria()
+            .is_none());
+
+        let ignore_case = MatchOptions {
+            ignore_case: true,
+            ..Default::default()
+        };
+        let filter = Filters::subject_contains(Pattern::new("VERIFICACIÓN", ignore_case));
+        assert!(filter.filter(&msg));
+        assert!(filter.search_criteria().is_none());
+        assert_eq!(
+            Filters::subject_contains(Pattern::new(
+                "CODE",
+                MatchOptions {
+                    ignore_case: true,
+                    ..Default::default()
+                }
+            ))
+            .search_criteria()
+            .unwrap()
+            .to_string(),
+            r#"SUBJECT "CODE""#
+        );
+    }
+
+    #[test]
+    fn test_date_filters() {
+        use chrono::TimeZone;
+
+        let raw = b"Received: from mx.example.com by mail.example.org; Wed, 2 Jul 2003 10:52:37 +0000\r\n\
+            Received: from sender.example.com by mx.example.com; Mon, 30 Jun 2003 10:52:30 +0000\r\n\
+            Date: Mon, 1 Jan 2024 00:00:00 +0000\r\n\
+            Subject: Forged date\r\n\r\n\
+            Body\r\n";
+        let msg = MessageParser::new().parse(&raw[..]).unwrap().into_owned();
+        let day = |year, month, day| Utc.with_ymd_and_hms(year, month, day, 0, 0, 0).unwrap();
+
+        assert!(Filters::sent_between(day(2023, 12, 31), day(2024, 1, 2)).filter(&msg));
+        assert!(Filters::sent_before(day(2024, 1, 2)).filter(&msg));
+        assert!(!Filters::sent_within(TimeDelta::minutes(10)).filter(&msg));
+        assert!(Filters::received_between(day(2003, 7, 2), day(2003, 7, 3)).filter(&msg));
+        assert!(Filters::received_since(day(2003, 7, 1)).filter(&msg));
+        assert!(!Filters::received_before(day(2003, 7, 1)).filter(&msg));
+        assert!(Filters::received_before(day(2003, 7, 2))
+            .search_criteria()
+            .is_none());
+        assert!(Filters::not(Filters::received_since(day(2004, 1, 1))).filter(&msg));
+        assert!(Filters::subject("Forged date")
+            .and_not(Filters::sent_since(day(2024, 1, 2)))
+            .filter(&msg));
+    }
+}
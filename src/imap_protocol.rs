@@ -1,12 +1,25 @@
-use async_imap::types::NameAttribute;
-use futures::StreamExt;
+use std::{collections::HashMap, time::Duration};
+
+use async_imap::{
+    extensions::idle::IdleResponse,
+    imap_proto::{self, MailboxDatum, Response},
+    types::{Fetch, NameAttribute},
+};
+use async_stream::try_stream;
+use futures::{pin_mut, stream::BoxStream, Stream, StreamExt, TryStreamExt};
 use mail_parser::MessageParser;
 use proxied::Proxy;
+use tokio::time::Instant;
 
 use crate::{
-    common,
-    server_map::{self},
-    Conn, DynEmailReader, Error, Filter, Mailbox, OwnedMessage,
+    common::{self, ReadTimeout},
+    message::{self, EmailAddress, Envelope},
+    oauth,
+    server_map::{self, Security},
+    sync::{FolderCursor, SyncState},
+    watch::{WatchEvent, WatchOptions},
+    Conn, ConnectOptions, DynEmailReader, Error, Filter, Flag, IdentifiedMessage, Mailbox,
+    MessageId, OwnedMessage, Protocol, TimeoutPhase,
 };
 
 pub struct PlainAuth {
@@ -40,51 +53,227 @@ impl<'a> async_imap::Authenticator for OAuth2<'a> {
     }
 }
 
+type Session = async_imap::Session<Box<dyn Conn>>;
+
 pub struct ImapProtocol {
-    session: async_imap::Session<Box<dyn Conn>>,
+    /// `None` if connection was lost, restored on the next use
+    session: Option<Session>,
+    mailbox: Mailbox,
+    endpoint: server_map::Imap,
+    proxy: Option<Proxy>,
+    options: ConnectOptions,
+    /// Shared with every connection of this reader, suspended during IDLE
+    read_timeout: ReadTimeout,
+    /// UIDs, flagged as `\Deleted` but not expunged yet, keyed by folder
+    deleted: HashMap<String, Vec<u32>>,
+}
+
+fn parse_fetch(parser: &MessageParser, fetch: &Fetch) -> Result<OwnedMessage, Error> {
+    let body = fetch.body().unwrap_or_default();
+
+    parser
+        .parse(body)
+        .map(|x| x.into_owned())
+        .ok_or(Error::MessageParseFailed)
+}
+
+fn message_id(folder: &str, uid_validity: u32, fetch: &Fetch) -> Result<MessageId, Error> {
+    let uid = fetch
+        .uid
+        .ok_or(Error::Imap(async_imap::error::Error::Parse(
+            async_imap::error::ParseError::ExpectedResponseNotFound("UID".to_owned()),
+        )))?;
+
+    Ok(MessageId::Imap {
+        folder: folder.to_owned(),
+        uid_validity,
+        uid,
+    })
+}
+
+/// Decodes text of ENVELOPE, which may contain RFC 2047 encoded words, the same way as the header of message
+fn decode_envelope_text(raw: &[u8]) -> Option<String> {
+    let header = [b"Subject: ", raw, b"\r\n\r\n"].concat();
+
+    MessageParser::new()
+        .parse_headers(&header)?
+        .subject()
+        .map(str::to_owned)
+}
+
+fn envelope_addresses(addresses: Option<&[imap_proto::Address]>) -> Vec<EmailAddress> {
+    addresses
+        .into_iter()
+        .flatten()
+        // Start and end markers of address groups have no host
+        .filter_map(|address| {
+            let mailbox = String::from_utf8_lossy(address.mailbox.as_deref()?);
+            let host = String::from_utf8_lossy(address.host.as_deref()?);
+
+            Some(EmailAddress {
+                name: address.name.as_deref().and_then(decode_envelope_text),
+                email: Some(format!("{mailbox}@{host}")),
+            })
+        })
+        .collect()
+}
+
+fn flag_name(flag: async_imap::types::Flag) -> String {
+    use async_imap::types::Flag as ImapFlag;
+
+    match flag {
+        ImapFlag::Seen => "\\Seen".to_owned(),
+        ImapFlag::Answered => "\\Answered".to_owned(),
+        ImapFlag::Flagged => "\\Flagged".to_owned(),
+        ImapFlag::Deleted => "\\Deleted".to_owned(),
+        ImapFlag::Draft => "\\Draft".to_owned(),
+        ImapFlag::Recent => "\\Recent".to_owned(),
+        ImapFlag::MayCreate => "\\*".to_owned(),
+        ImapFlag::Custom(flag) => flag.into_owned(),
+    }
+}
+
+/// Envelope of `fetch` with `(UID RFC822.SIZE FLAGS ENVELOPE)`
+fn parse_envelope(id: MessageId, fetch: &Fetch) -> Envelope {
+    let envelope = fetch.envelope();
+
+    let date = envelope.and_then(|envelope| envelope.date.as_deref());
+    let date = date.and_then(|date| {
+        let header = [b"Date: ", date, b"\r\n\r\n"].concat();
+        message::header_date(&MessageParser::new().parse_headers(&header)?)
+    });
+
+    Envelope {
+        id,
+        from: envelope_addresses(envelope.and_then(|envelope| envelope.from.as_deref())),
+        to: envelope_addresses(envelope.and_then(|envelope| envelope.to.as_deref())),
+        subject: envelope
+            .and_then(|envelope| envelope.subject.as_deref())
+            .and_then(decode_envelope_text),
+        date,
+        size: fetch.size.unwrap_or_default().into(),
+        flags: fetch.flags().map(flag_name).collect(),
+    }
+}
+
+/// Folder name as IMAP quoted string, the same way `async_imap` quotes it for SELECT
+fn quote_folder(folder: &str) -> Result<String, Error> {
+    if folder.contains(['\r', '\n']) {
+        return Err(Error::InvalidFolder {
+            folder: folder.to_owned(),
+        });
+    }
+
+    Ok(format!(
+        "\"{}\"",
+        folder.replace('\\', "\\\\").replace('"', "\\\"")
+    ))
+}
+
+/// Formats UIDs as IMAP sequence set, collapsing consecutive runs to `first:last`
+fn compress_uid_set(uids: impl IntoIterator<Item = u32>) -> String {
+    let mut uids = uids.into_iter().collect::<Vec<_>>();
+    uids.sort_unstable();
+
+    let mut ranges: Vec<(u32, u32)> = Vec::new();
+    for uid in uids {
+        match ranges.last_mut() {
+            Some((_, last)) if *last + 1 == uid => *last = uid,
+            _ => ranges.push((uid, uid)),
+        }
+    }
+
+    ranges
+        .into_iter()
+        .map(|(first, last)| match first == last {
+            true => first.to_string(),
+            false => format!("{first}:{last}"),
+        })
+        .collect::<Vec<_>>()
+        .join(",")
 }
 
 impl ImapProtocol {
-    pub async fn crawl_messages(
-        &mut self,
-        folder: &str,
-        filter: &impl Filter,
-    ) -> Result<Vec<OwnedMessage>, Error> {
-        let mailbox = self.session.select(folder).await?;
-        let fetch_result = self
-            .session
-            .fetch(format!("1:{}", mailbox.exists), "RFC822")
-            .await?
-            .collect::<Vec<_>>()
-            .await;
+    /// Authenticated session, reconnecting if the previous one was lost
+    async fn session(&mut self) -> Result<&mut Session, Error> {
+        let session = match self.session {
+            Some(ref mut session) => session,
+            None => {
+                let session = ImapConnector::connect_session(
+                    &mut self.mailbox,
+                    &self.endpoint,
+                    self.proxy.clone(),
+                    &self.options,
+                    self.read_timeout.clone(),
+                )
+                .await?;
+                self.session.insert(session)
+            }
+        };
 
-        let mut res = Vec::new();
+        Ok(session)
+    }
 
-        let msg_parser = MessageParser::new();
-        for fetch in fetch_result.into_iter() {
-            let fetch = fetch?;
-            let body = fetch
-                .body()
-                .map(|body| body.to_vec())
-                .unwrap_or_else(Vec::new);
+    pub fn stream_messages<'a>(
+        &'a mut self,
+        folder: &'a str,
+        filter: &'a impl Filter,
+    ) -> impl Stream<Item = Result<IdentifiedMessage, Error>> + Send + 'a {
+        try_stream! {
+            let mailbox = self.session().await?.select(folder).await?;
+            if mailbox.exists == 0 {
+                return;
+            }
+            let uid_validity = mailbox.uid_validity.unwrap_or_default();
 
-            let new_msg = msg_parser
-                .parse(body.as_slice())
-                .map(|x| x.into_owned())
-                .ok_or(Error::MessageParseFailed)?;
+            let Some(uid_set) = self.uid_set_to_fetch("1:*".to_owned(), filter).await? else {
+                return;
+            };
 
-            if filter.filter(&new_msg) {
-                res.push(new_msg);
+            let mut fetch_stream = self.session().await?.uid_fetch(uid_set, "(UID RFC822)").await?;
+
+            let msg_parser = MessageParser::new();
+            while let Some(fetch) = fetch_stream.next().await {
+                let fetch = fetch?;
+                let new_msg = parse_fetch(&msg_parser, &fetch)?;
+
+                if filter.filter(&new_msg) {
+                    yield (message_id(folder, uid_validity, &fetch)?, new_msg);
+                }
             }
         }
+    }
+
+    /// UIDs from `range` of selected folder, which are worth downloading
+    ///
+    /// Asks server to SEARCH for them, if `filter` can be expressed as criteria.
+    /// `None` if nothing matches
+    async fn uid_set_to_fetch(
+        &mut self,
+        range: String,
+        filter: &impl Filter,
+    ) -> Result<Option<String>, Error> {
+        let Some(criteria) = filter.search_criteria() else {
+            return Ok(Some(range));
+        };
+
+        let uids = self
+            .session()
+            .await?
+            .uid_search(format!("UID {range} {criteria}"))
+            .await?;
+
+        if uids.is_empty() {
+            return Ok(None);
+        }
 
-        Ok(res)
+        Ok(Some(compress_uid_set(uids)))
     }
 
     async fn get_folders(&mut self) -> Result<Vec<String>, Error> {
         let mut server_folders = vec![];
 
-        let mut stream = self.session.list(None, Some("*")).await?;
+        let mut stream = self.session().await?.list(None, Some("*")).await?;
 
         while let Some(item) = stream.next().await {
             match item {
@@ -100,64 +289,680 @@ impl ImapProtocol {
                 }
 
                 Err(err) => {
-                    return Err(Error::Imap(err));
+                    return Err(err.into());
                 }
             }
         }
 
         Ok(server_folders)
     }
-    pub async fn crawl_folders(
+
+    pub fn stream_folders<'a>(
+        &'a mut self,
+        folders: &'a [String],
+        filter: &'a impl Filter,
+    ) -> impl Stream<Item = Result<IdentifiedMessage, Error>> + Send + 'a {
+        try_stream! {
+            for folder in folders.iter() {
+                let messages = self.stream_messages(folder, filter);
+                pin_mut!(messages);
+
+                while let Some(msg) = messages.next().await {
+                    yield msg?;
+                }
+            }
+        }
+    }
+
+    async fn sync_folder(
         &mut self,
-        folders: &Vec<String>,
+        folder: &str,
         filter: &impl Filter,
-    ) -> Result<Vec<OwnedMessage>, Error> {
-        let mut res = Vec::new();
+        state: &SyncState,
+        found: &mut Vec<IdentifiedMessage>,
+    ) -> Result<FolderCursor, Error> {
+        let mailbox = self.session().await?.select(folder).await?;
+        let uid_validity = mailbox.uid_validity.unwrap_or_default();
+        let mut cursor = FolderCursor::resume(state, folder, uid_validity);
+        let last_uid = cursor.last_uid;
+
+        // Messages, arriving after SELECT, are left for the next sync
+        let range = match cursor.next_range(mailbox.uid_next) {
+            Some(range) if mailbox.exists > 0 => range,
+            _ => return Ok(cursor),
+        };
+
+        let Some(uid_set) = self.uid_set_to_fetch(range, filter).await? else {
+            return Ok(cursor);
+        };
+
+        let mut fetch_stream = self
+            .session()
+            .await?
+            .uid_fetch(uid_set, "(UID RFC822)")
+            .await?;
 
-        for folder in folders.iter() {
-            res.extend(self.crawl_messages(folder, filter).await?.into_iter());
+        let msg_parser = MessageParser::new();
+        while let Some(fetch) = fetch_stream.next().await {
+            let fetch = fetch?;
+            let Some(uid) = fetch.uid.filter(|uid| *uid > last_uid) else {
+                continue;
+            };
+            cursor.advance(uid);
+
+            let new_msg = parse_fetch(&msg_parser, &fetch)?;
+            if filter.filter(&new_msg) {
+                found.push((message_id(folder, uid_validity, &fetch)?, new_msg));
+            }
         }
 
-        Ok(res)
+        Ok(cursor)
     }
 
-    async fn get_filtered_emails(
+    async fn sync_filtered_emails(
         &mut self,
         filter: impl Filter,
-    ) -> Result<Vec<OwnedMessage>, Error> {
+        state: SyncState,
+    ) -> Result<(Vec<IdentifiedMessage>, SyncState), Error> {
+        let folders = self.get_folders().await?;
+
+        let mut found = Vec::new();
+        let mut new_state = SyncState {
+            imap: HashMap::new(),
+            pop3: state.pop3.clone(),
+        };
+
+        for folder in folders {
+            let cursor = self
+                .sync_folder(&folder, &filter, &state, &mut found)
+                .await?;
+            new_state.imap.insert(folder, cursor);
+        }
+
+        Ok((found, new_state))
+    }
+
+    /// Sync state, marking every message, which is currently in the mailbox, as seen
+    async fn current_sync_state(&mut self) -> Result<SyncState, Error> {
         let folders = self.get_folders().await?;
 
-        self.crawl_folders(&folders, &filter).await
+        let mut state = SyncState::new();
+        for folder in folders {
+            let mailbox = self.session().await?.select(&folder).await?;
+            let last_uid = match mailbox.uid_next {
+                Some(next) => next.saturating_sub(1),
+                None => self
+                    .session()
+                    .await?
+                    .uid_search("ALL")
+                    .await?
+                    .into_iter()
+                    .max()
+                    .unwrap_or(0),
+            };
+
+            let cursor = FolderCursor {
+                uid_validity: mailbox.uid_validity.unwrap_or_default(),
+                last_uid,
+            };
+            state.imap.insert(folder, cursor);
+        }
+
+        Ok(state)
+    }
+
+    async fn list_envelopes(&mut self) -> Result<Vec<Envelope>, Error> {
+        let folders = self.get_folders().await?;
+
+        let mut envelopes = Vec::new();
+        for folder in folders {
+            let mailbox = self.session().await?.select(&folder).await?;
+            if mailbox.exists == 0 {
+                continue;
+            }
+            let uid_validity = mailbox.uid_validity.unwrap_or_default();
+
+            let mut fetch_stream = self
+                .session()
+                .await?
+                .uid_fetch("1:*", "(UID RFC822.SIZE FLAGS ENVELOPE)")
+                .await?;
+
+            while let Some(fetch) = fetch_stream.next().await {
+                let fetch = fetch?;
+                let id = message_id(&folder, uid_validity, &fetch)?;
+                envelopes.push(parse_envelope(id, &fetch));
+            }
+        }
+
+        Ok(envelopes)
+    }
+
+    async fn fetch(&mut self, id: &MessageId) -> Result<OwnedMessage, Error> {
+        let (_, uid) = self.select_message(id).await?;
+
+        // PEEK doesn't set \Seen, so looking into a message doesn't change the mailbox
+        let fetches: Vec<Fetch> = self
+            .session()
+            .await?
+            .uid_fetch(uid.to_string(), "(UID BODY.PEEK[])")
+            .await?
+            .try_collect()
+            .await?;
+
+        // Server may also send FETCH responses of other messages, e.g. if their flags changed
+        let fetch = fetches
+            .iter()
+            .find(|fetch| fetch.uid == Some(uid))
+            .ok_or_else(|| Error::MessageNotFound { id: id.clone() })?;
+
+        parse_fetch(&MessageParser::new(), fetch)
+    }
+
+    /// Selects folder of `id`, making sure its UIDs still refer to the same messages
+    async fn select_message<'id>(&mut self, id: &'id MessageId) -> Result<(&'id str, u32), Error> {
+        let MessageId::Imap {
+            folder,
+            uid_validity,
+            uid,
+        } = id
+        else {
+            return Err(Error::MessageNotFound { id: id.clone() });
+        };
+
+        let mailbox = self.session().await?.select(folder).await?;
+        if mailbox.uid_validity.unwrap_or_default() != *uid_validity {
+            return Err(Error::MessageNotFound { id: id.clone() });
+        }
+
+        Ok((folder, *uid))
+    }
+
+    async fn store(&mut self, id: &MessageId, query: &str) -> Result<(), Error> {
+        let (_, uid) = self.select_message(id).await?;
+        self.store_selected(uid, query).await
+    }
+
+    /// Same as `store`, but for message of already selected folder
+    async fn store_selected(&mut self, uid: u32, query: &str) -> Result<(), Error> {
+        self.session()
+            .await?
+            .uid_store(uid.to_string(), query)
+            .await?
+            .try_collect::<Vec<_>>()
+            .await?;
+
+        Ok(())
+    }
+
+    /// Returns UID of the original, its folder stays selected
+    async fn copy(&mut self, id: &MessageId, folder: &str) -> Result<u32, Error> {
+        let destination = quote_folder(folder)?;
+        let (_, uid) = self.select_message(id).await?;
+
+        self.session()
+            .await?
+            .uid_copy(uid.to_string(), destination)
+            .await?;
+
+        Ok(uid)
+    }
+
+    async fn move_message(&mut self, id: &MessageId, folder: &str) -> Result<(), Error> {
+        let capabilities = self.session().await?.capabilities().await?;
+
+        if capabilities.has_str("MOVE") {
+            let (_, uid) = self.select_message(id).await?;
+            self.session()
+                .await?
+                .uid_mv(uid.to_string(), folder)
+                .await?;
+
+            return Ok(());
+        }
+
+        let uid = self.copy(id, folder).await?;
+        if !capabilities.has_str("UIDPLUS") {
+            return self.delete(id).await;
+        }
+
+        self.store_selected(uid, "+FLAGS.SILENT (\\Deleted)")
+            .await?;
+        self.session()
+            .await?
+            .uid_expunge(uid.to_string())
+            .await?
+            .try_collect::<Vec<_>>()
+            .await?;
+
+        Ok(())
+    }
+
+    async fn delete(&mut self, id: &MessageId) -> Result<(), Error> {
+        self.store(id, "+FLAGS.SILENT (\\Deleted)").await?;
+
+        if let MessageId::Imap { folder, uid, .. } = id {
+            self.deleted.entry(folder.clone()).or_default().push(*uid);
+        }
+
+        Ok(())
+    }
+
+    /// Expunges deleted messages, only the ones deleted by this reader if server supports UIDPLUS
+    async fn commit(&mut self) -> Result<(), Error> {
+        let supports_uidplus = self
+            .session()
+            .await?
+            .capabilities()
+            .await?
+            .has_str("UIDPLUS");
+
+        for (folder, uids) in std::mem::take(&mut self.deleted) {
+            let session = self.session().await?;
+            session.select(&folder).await?;
+
+            match supports_uidplus {
+                true => {
+                    session
+                        .uid_expunge(compress_uid_set(uids))
+                        .await?
+                        .try_collect::<Vec<_>>()
+                        .await?;
+                }
+                false => {
+                    session.expunge().await?.try_collect::<Vec<_>>().await?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn rollback(&mut self) -> Result<(), Error> {
+        for (folder, uids) in std::mem::take(&mut self.deleted) {
+            let session = self.session().await?;
+            session.select(&folder).await?;
+            session
+                .uid_store(compress_uid_set(uids), "-FLAGS.SILENT (\\Deleted)")
+                .await?
+                .try_collect::<Vec<_>>()
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    /// Blocks in IDLE until `folder` reports new EXISTS or `timeout` passes
+    ///
+    /// Returns latest EXISTS of `folder`. If it already differs from `known_exists`, returns without idling.
+    /// If anything goes wrong, the session is dropped and restored on the next use
+    async fn idle(
+        &mut self,
+        folder: &str,
+        timeout: Duration,
+        known_exists: Option<u32>,
+    ) -> Result<u32, Error> {
+        let mailbox = self.session().await?.select(folder).await?;
+        if known_exists.is_some_and(|known| known != mailbox.exists) {
+            return Ok(mailbox.exists);
+        }
+
+        let deadline = Instant::now() + timeout;
+        let mut handle = self
+            .session
+            .take()
+            .expect("session was just restored")
+            .idle();
+        handle.init().await?;
+
+        let mut exists = mailbox.exists;
+        // Server stays silent in IDLE until something happens, `timeout` bounds the wait instead
+        let suspended = self.read_timeout.suspend();
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            let (idle_wait, _interrupt) = handle.wait_with_timeout(remaining);
+
+            let IdleResponse::NewData(data) = idle_wait.await? else {
+                break;
+            };
+            match data.parsed() {
+                Response::MailboxData(MailboxDatum::Exists(new_exists))
+                    if *new_exists != exists =>
+                {
+                    exists = *new_exists;
+                    break;
+                }
+                // Server doesn't resend EXISTS after EXPUNGE, so the next new message
+                // would bring EXISTS back to the known value
+                Response::Expunge(_) => {
+                    exists = exists.saturating_sub(1);
+                    break;
+                }
+                _ => {}
+            }
+        }
+        drop(suspended);
+        self.session = Some(handle.done().await?);
+
+        Ok(exists)
+    }
+
+    /// Restores dropped session, giving up after `options.max_reconnect_attempts`
+    async fn reconnect(&mut self, mut err: Error, options: &WatchOptions) -> Result<(), Error> {
+        for attempt in 1..=options.max_reconnect_attempts {
+            tracing::warn!(%err, attempt, "imap connection lost, reconnecting");
+            tokio::time::sleep(options.reconnect_delay * attempt).await;
+
+            match self.session().await {
+                Ok(_) => return Ok(()),
+                Err(reconnect_err) => err = reconnect_err,
+            }
+        }
+
+        Err(err)
+    }
+
+    /// Keeps IDLE in `options.folder`, emitting an event whenever its EXISTS changes
+    ///
+    /// IDLE is reissued every `options.renew_interval`, lost connections are restored
+    pub fn watch(
+        mut self,
+        options: WatchOptions,
+    ) -> impl Stream<Item = Result<WatchEvent, Error>> + Send {
+        try_stream! {
+            let session = self.session().await?;
+            if !session.capabilities().await?.has_str("IDLE") {
+                Err(Error::Unsupported {
+                    operation: "idle",
+                    protocol: Protocol::Imap,
+                })?;
+            }
+
+            let mut known_exists = self.session().await?.select(&options.folder).await?.exists;
+            yield WatchEvent::Exists(known_exists);
+
+            // `reconnect` counts only its own attempts, so IDLE failing right after every
+            // successful reconnect is counted here
+            let mut failures = 0;
+            loop {
+                let err = match self
+                    .idle(&options.folder, options.renew_interval, Some(known_exists))
+                    .await
+                {
+                    Ok(exists) => {
+                        failures = 0;
+                        if exists != known_exists {
+                            known_exists = exists;
+                            yield WatchEvent::Exists(exists);
+                        }
+                        continue;
+                    }
+                    Err(err) => err,
+                };
+
+                failures += 1;
+                if failures > options.max_reconnect_attempts {
+                    Err(err)?;
+                } else {
+                    self.session = None;
+                    self.reconnect(err, &options).await?;
+                    yield WatchEvent::Reconnected;
+                }
+            }
+        }
+    }
+
+    async fn wait_for_email(
+        &mut self,
+        filter: impl Filter,
+        timeout: Duration,
+        poll_interval: Duration,
+    ) -> Result<IdentifiedMessage, Error> {
+        let deadline = Instant::now() + timeout;
+
+        let supports_idle = self.session().await?.capabilities().await?.has_str("IDLE");
+        let mut state = self.current_sync_state().await?;
+
+        loop {
+            let (found, new_state) = self.sync_filtered_emails(&filter, state).await?;
+            if let Some(msg) = found.into_iter().next() {
+                return Ok(msg);
+            }
+            state = new_state;
+
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Err(Error::WaitTimeout);
+            }
+
+            // IDLE only watches INBOX, other folders are rechecked every `poll_interval`
+            let pause = poll_interval.min(remaining);
+            match supports_idle {
+                true => _ = self.idle("INBOX", pause, None).await?,
+                false => tokio::time::sleep(pause).await,
+            }
+        }
+    }
+
+    fn stream_filtered_emails<'a>(
+        &'a mut self,
+        filter: impl Filter + 'a,
+    ) -> impl Stream<Item = Result<IdentifiedMessage, Error>> + Send + 'a {
+        try_stream! {
+            let folders = self.get_folders().await?;
+
+            let messages = self.stream_folders(&folders, &filter);
+            pin_mut!(messages);
+
+            while let Some(msg) = messages.next().await {
+                yield msg?;
+            }
+        }
     }
 }
 
 #[async_trait::async_trait]
 impl DynEmailReader for ImapProtocol {
-    async fn dyn_get_filtered_emails(
+    fn dyn_stream_identified_emails(
         &mut self,
         filter: Box<dyn Filter>,
-    ) -> Result<Vec<OwnedMessage>, Error> {
-        self.get_filtered_emails(filter).await
+    ) -> BoxStream<'_, Result<IdentifiedMessage, Error>> {
+        self.stream_filtered_emails(filter).boxed()
+    }
+
+    async fn dyn_sync_identified_emails(
+        &mut self,
+        filter: Box<dyn Filter>,
+        state: SyncState,
+    ) -> Result<(Vec<IdentifiedMessage>, SyncState), Error> {
+        self.sync_filtered_emails(filter, state).await
+    }
+
+    async fn dyn_wait_for_identified_email(
+        &mut self,
+        filter: Box<dyn Filter>,
+        timeout: Duration,
+        poll_interval: Duration,
+    ) -> Result<IdentifiedMessage, Error> {
+        self.wait_for_email(filter, timeout, poll_interval).await
+    }
+
+    async fn dyn_list_envelopes(&mut self) -> Result<Vec<Envelope>, Error> {
+        self.list_envelopes().await
+    }
+
+    async fn dyn_fetch(&mut self, id: &MessageId) -> Result<OwnedMessage, Error> {
+        self.fetch(id).await
+    }
+
+    async fn dyn_flag(&mut self, id: &MessageId, flag: Flag) -> Result<(), Error> {
+        let query = format!("+FLAGS.SILENT ({})", flag.to_imap()?);
+        self.store(id, &query).await
+    }
+
+    async fn dyn_unflag(&mut self, id: &MessageId, flag: Flag) -> Result<(), Error> {
+        let query = format!("-FLAGS.SILENT ({})", flag.to_imap()?);
+        self.store(id, &query).await
+    }
+
+    async fn dyn_copy(&mut self, id: &MessageId, folder: &str) -> Result<(), Error> {
+        self.copy(id, folder).await.map(drop)
+    }
+
+    async fn dyn_move(&mut self, id: &MessageId, folder: &str) -> Result<(), Error> {
+        self.move_message(id, folder).await
+    }
+
+    async fn dyn_delete(&mut self, id: &MessageId) -> Result<(), Error> {
+        self.delete(id).await
+    }
+
+    async fn dyn_commit(&mut self) -> Result<(), Error> {
+        self.commit().await
+    }
+
+    async fn dyn_rollback(&mut self) -> Result<(), Error> {
+        self.rollback().await
+    }
+
+    fn dyn_mailbox(&self) -> &Mailbox {
+        &self.mailbox
     }
 }
 
 pub struct ImapConnector;
 impl ImapConnector {
     pub async fn connect(
-        mailbox: Mailbox,
-        server_map::Imap(endpoint): &server_map::Imap,
+        mut mailbox: Mailbox,
+        endpoint: &server_map::Imap,
         proxy: Option<Proxy>,
+        options: &ConnectOptions,
     ) -> Result<ImapProtocol, Error> {
-        let stream = common::connect_maybe_proxied_stream_tls(
+        let read_timeout = ReadTimeout::new(options.timeouts.command);
+        let session = Self::connect_session(
+            &mut mailbox,
+            endpoint,
+            proxy.clone(),
+            options,
+            read_timeout.clone(),
+        )
+        .await?;
+
+        Ok(ImapProtocol {
+            session: Some(session),
+            mailbox,
+            endpoint: endpoint.clone(),
+            proxy,
+            options: options.clone(),
+            read_timeout,
+            deleted: HashMap::new(),
+        })
+    }
+
+    /// Connects and logs in, refreshing OAuth2 tokens of `mailbox` if needed
+    async fn connect_session(
+        mailbox: &mut Mailbox,
+        endpoint: &server_map::Imap,
+        proxy: Option<Proxy>,
+        options: &ConnectOptions,
+        read_timeout: ReadTimeout,
+    ) -> Result<Session, Error> {
+        oauth::login_with_refresh(
+            mailbox,
+            options.token_refresher.as_deref(),
+            Protocol::Imap,
+            &endpoint.0,
+            |mailbox| {
+                Self::login(
+                    mailbox,
+                    endpoint,
+                    proxy.clone(),
+                    options,
+                    read_timeout.clone(),
+                )
+            },
+        )
+        .await
+    }
+
+    /// Unauthenticated client, secured according to `endpoint.security`
+    async fn connect_client(
+        endpoint: &server_map::Endpoint,
+        proxy: Option<Proxy>,
+        options: &ConnectOptions,
+        read_timeout: ReadTimeout,
+    ) -> Result<async_imap::Client<Box<dyn Conn>>, Error> {
+        let ConnectOptions { tls, timeouts, .. } = options;
+        let stream = common::connect_maybe_proxied_stream(
             endpoint.domain.clone(),
             endpoint.port,
-            proxy.clone(),
+            proxy,
+            timeouts,
+            read_timeout,
         )
         .await?;
 
-        let mut client: async_imap::Client<Box<dyn Conn>> =
-            async_imap::Client::new(Box::new(stream));
+        let mut client = match endpoint.security {
+            Security::Tls => {
+                let stream =
+                    common::upgrade_tls(endpoint.domain.clone(), stream, tls, timeouts).await?;
+                return Ok(async_imap::Client::new(stream));
+            }
+            Security::Plain => return Ok(async_imap::Client::new(stream)),
+            Security::StartTls | Security::StartTlsOpportunistic => async_imap::Client::new(stream),
+        };
+
+        // Greeting is skipped as untagged response. Servers without STARTTLS reject it with NO or BAD
+        match client.run_command_and_check_ok("STARTTLS", None).await {
+            Ok(()) => {
+                let stream = common::upgrade_tls(
+                    endpoint.domain.clone(),
+                    client.into_inner(),
+                    tls,
+                    timeouts,
+                )
+                .await?;
+                Ok(async_imap::Client::new(stream))
+            }
+            Err(async_imap::error::Error::No(_) | async_imap::error::Error::Bad(_))
+                if endpoint.security == Security::StartTlsOpportunistic =>
+            {
+                tracing::warn!(
+                    domain = endpoint.domain,
+                    "imap server doesn't support STARTTLS, staying in plaintext"
+                );
+                Ok(client)
+            }
+            Err(async_imap::error::Error::No(_) | async_imap::error::Error::Bad(_)) => {
+                Err(Error::StartTlsUnsupported {
+                    domain: endpoint.domain.clone(),
+                })
+            }
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    async fn login(
+        mailbox: Mailbox,
+        server_map::Imap(endpoint): &server_map::Imap,
+        proxy: Option<Proxy>,
+        options: &ConnectOptions,
+        read_timeout: ReadTimeout,
+    ) -> Result<Session, Error> {
+        let client = Self::connect_client(endpoint, proxy, options, read_timeout).await?;
+
+        common::with_timeout(
+            TimeoutPhase::Login,
+            options.timeouts.login,
+            Self::authenticate(client, &mailbox),
+        )
+        .await
+    }
 
+    async fn authenticate(
+        mut client: async_imap::Client<Box<dyn Conn>>,
+        mailbox: &Mailbox,
+    ) -> Result<Session, Error> {
         client.run_command_and_check_ok("CAPABILITY", None).await?;
 
         let client = match mailbox.oauth2.as_ref() {
@@ -167,7 +972,7 @@ impl ImapConnector {
                         "PLAIN",
                         PlainAuth {
                             login: mailbox.email.clone(),
-                            password: mailbox.password,
+                            password: mailbox.password.clone(),
                         },
                     )
                     .await
@@ -184,12 +989,14 @@ impl ImapConnector {
                     .await
             }
         }
-        .map_err(|x| x.0)?;
+        .map_err(|(err, _)| match err {
+            async_imap::error::Error::No(response) | async_imap::error::Error::Bad(response) => {
+                Error::login_rejected(crate::imap_response_text(&response))
+            }
+            err => err.into(),
+        })?;
         // let client = client.login(creds.0, creds.1).await.map_err(|x| x.0)?;
 
-        Ok(ImapProtocol {
-            session: client,
-            // mail: mailbox.clone(),
-        })
+        Ok(client)
     }
 }
@@ -1,13 +1,24 @@
 use chrono::{DateTime, Utc};
+use dns::DnsResolver;
+use futures::{
+    stream::{BoxStream, FuturesUnordered},
+    StreamExt, TryStreamExt,
+};
 use imap_protocol::ImapConnector;
 pub use mail_parser;
 use mail_parser::{Message, MessageParser};
 use pop3_protocol::Pop3Connector;
 use proxied::Proxy;
-use server_map::{ArcMap, ServerMap};
+use server_map::{ArcMap, Endpoint, Endpoints, Server, ServerMap};
+use std::{future::Future, sync::Arc, time::Duration};
 use tokio::io::AsyncWrite;
 
+pub mod autoconfig;
+pub mod dns;
 pub mod filters;
+pub mod message;
+pub mod oauth;
+pub mod pool;
 
 mod common;
 mod imap_protocol;
@@ -17,6 +28,15 @@ trait Conn: tokio::io::AsyncRead + AsyncWrite + Unpin + Send + std::fmt::Debug {
 impl<T: tokio::io::AsyncRead + AsyncWrite + Unpin + Send + std::fmt::Debug> Conn for T {}
 
 pub mod server_map;
+pub mod sync;
+pub mod tls;
+pub mod watch;
+
+pub use message::{EmailAddress, Envelope, Flag, MessageId};
+pub use oauth::{HttpTokenRefresher, TokenRefresher};
+pub use pool::{MailboxPool, PoolOptions};
+pub use sync::SyncState;
+pub use tls::TlsOptions;
 
 #[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
 pub struct OAuthToken {
@@ -53,6 +73,9 @@ impl Mailbox {
 
 pub type OwnedMessage = Message<'static>;
 
+/// Message together with its identifier, see `DynEmailReader::dyn_delete`, etc.
+pub type IdentifiedMessage = (MessageId, OwnedMessage);
+
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
     #[error("this mailbox is invalid: can't distinguish domain part")]
@@ -61,23 +84,421 @@ pub enum Error {
     #[error("couldn't find server entry for this mailbox: {domain}")]
     ServerNotFound { domain: String },
 
+    #[error("server entry for this mailbox has no imap endpoint: {domain}")]
+    ImapNotFound { domain: String },
+
     #[error("couldn't parse message")]
     MessageParseFailed,
 
     #[error("Imap-specific error")]
-    Imap(#[from] async_imap::error::Error),
+    Imap(#[source] async_imap::error::Error),
+
+    #[error("imap server rejected command: {response}")]
+    ImapRejected { response: String },
 
     #[error("pop-specific erro")]
-    Pop(#[from] async_pop2::error::Error),
+    Pop(#[source] async_pop2::error::Error),
 
     #[error("failed connection to proxy")]
     Proxy(#[from] proxied::ConnectError),
 
+    #[error("server doesn't support STARTTLS, but endpoint requires it: {domain}")]
+    StartTlsUnsupported { domain: String },
+
+    #[error("invalid dns name of email server: {domain}")]
+    InvalidDnsName { domain: String },
+
+    #[error("invalid tls configuration")]
+    TlsConfig(#[source] Box<dyn std::error::Error + Send + Sync>),
+
+    #[error("tls handshake failed")]
+    TlsHandshake(#[source] std::io::Error),
+
+    #[error("dns lookup of {domain} failed")]
+    DnsLookup {
+        domain: String,
+        #[source]
+        source: Box<dyn std::error::Error + Send + Sync>,
+    },
+
     #[error("failed to resolve dns of email server")]
     ResolveDns,
 
+    #[error("no matching email arrived before the deadline")]
+    WaitTimeout,
+
+    #[error("failed to refresh oauth2 token")]
+    TokenRefresh(#[source] Box<dyn std::error::Error + Send + Sync>),
+
+    #[error("{phase} timed out")]
+    Timeout { phase: TimeoutPhase },
+
+    #[error("server rejected credentials: {response}")]
+    AuthenticationFailed { response: String },
+
+    #[error("account is locked or disabled: {response}")]
+    AccountLocked { response: String },
+
+    #[error("server is temporarily unavailable: {response}")]
+    ServerUnavailable { response: String },
+
+    #[error("{operation} isn't supported by {protocol}")]
+    Unsupported {
+        operation: &'static str,
+        protocol: Protocol,
+    },
+
+    #[error("message is no longer in mailbox or belongs to another one: {id:?}")]
+    MessageNotFound { id: MessageId },
+
+    #[error("invalid flag keyword: {keyword}")]
+    InvalidKeyword { keyword: String },
+
+    #[error("invalid folder name: {folder:?}")]
+    InvalidFolder { folder: String },
+
+    #[error("all connection attempts failed: {}", list_attempts(.attempts))]
+    AllAttemptsFailed { attempts: Vec<Error> },
+
+    #[error("{protocol} at {}:{} failed", endpoint.domain, endpoint.port)]
+    Attempt {
+        protocol: Protocol,
+        endpoint: Endpoint,
+        #[source]
+        source: Box<Error>,
+        /// Tokens refreshed during attempt, which caller should persist
+        oauth: Option<Box<OAuthData>>,
+    },
+
     #[error("socket failed")]
-    Socket(#[from] std::io::Error),
+    Socket(#[source] std::io::Error),
+}
+
+/// Category of `Error`, for deciding whether to retry, ask user for new credentials, etc.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ErrorKind {
+    /// Credentials or OAuth2 tokens were rejected
+    AuthenticationFailed,
+    /// Credentials may be right, but account is locked, disabled or needs attention of its owner
+    AccountLocked,
+    /// Server can't serve the account right now, e.g. it's under maintenance or mailbox is in use
+    ServerUnavailable,
+    Network,
+    Proxy,
+    Tls,
+    /// Server responded with an error or something unexpected
+    Protocol,
+    Timeout,
+    /// No endpoints are known for domain of mailbox
+    ServerNotFound,
+    /// Mailbox, options or arguments are invalid
+    InvalidInput,
+    /// Operation isn't supported by protocol or server
+    Unsupported,
+}
+
+/// Protocol of endpoint
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Protocol {
+    Imap,
+    Pop3,
+}
+
+/// Which protocols `connect_any` tries and in which order
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum ProtocolOrder {
+    #[default]
+    ImapFirst,
+    Pop3First,
+    ImapOnly,
+    /// Connect to both at once, the first one to log in wins and the other one is dropped
+    Race,
+}
+
+impl ProtocolOrder {
+    fn protocols(self) -> &'static [Protocol] {
+        match self {
+            ProtocolOrder::ImapFirst | ProtocolOrder::Race => &[Protocol::Imap, Protocol::Pop3],
+            ProtocolOrder::Pop3First => &[Protocol::Pop3, Protocol::Imap],
+            ProtocolOrder::ImapOnly => &[Protocol::Imap],
+        }
+    }
+}
+
+impl std::fmt::Display for Protocol {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Protocol::Imap => "imap",
+            Protocol::Pop3 => "pop3",
+        })
+    }
+}
+
+impl Error {
+    pub fn kind(&self) -> ErrorKind {
+        use async_imap::error::Error as ImapError;
+        use async_pop2::error::ErrorKind as PopErrorKind;
+
+        match self {
+            Error::MailboxInvalidDomain { .. }
+            | Error::MessageNotFound { .. }
+            | Error::InvalidKeyword { .. }
+            | Error::InvalidFolder { .. } => ErrorKind::InvalidInput,
+            Error::Unsupported { .. } => ErrorKind::Unsupported,
+            Error::ServerNotFound { .. } | Error::ImapNotFound { .. } => ErrorKind::ServerNotFound,
+            Error::MessageParseFailed => ErrorKind::Protocol,
+            Error::Imap(ImapError::Io(_) | ImapError::ConnectionLost) => ErrorKind::Network,
+            Error::Imap(_) | Error::ImapRejected { .. } => ErrorKind::Protocol,
+            Error::Pop(err) => match err.kind() {
+                PopErrorKind::Io(_)
+                | PopErrorKind::NotConnected
+                | PopErrorKind::ConnectionClosed => ErrorKind::Network,
+                _ => ErrorKind::Protocol,
+            },
+            Error::Proxy(_) => ErrorKind::Proxy,
+            Error::StartTlsUnsupported { .. }
+            | Error::InvalidDnsName { .. }
+            | Error::TlsConfig(_)
+            | Error::TlsHandshake(_) => ErrorKind::Tls,
+            Error::DnsLookup { .. } | Error::ResolveDns | Error::Socket(_) => ErrorKind::Network,
+            Error::WaitTimeout | Error::Timeout { .. } => ErrorKind::Timeout,
+            Error::TokenRefresh(source) => oauth::refresh_error_kind(source.as_ref()),
+            Error::AuthenticationFailed { .. } => ErrorKind::AuthenticationFailed,
+            Error::AccountLocked { .. } => ErrorKind::AccountLocked,
+            Error::ServerUnavailable { .. } => ErrorKind::ServerUnavailable,
+            Error::Attempt { source, .. } => source.kind(),
+            // Credentials are shared by all protocols, so their rejection outweighs other failures
+            Error::AllAttemptsFailed { attempts } => attempts
+                .iter()
+                .map(Error::kind)
+                .find(|kind| {
+                    matches!(
+                        kind,
+                        ErrorKind::AuthenticationFailed | ErrorKind::AccountLocked
+                    )
+                })
+                .or_else(|| attempts.first().map(Error::kind))
+                .unwrap_or(ErrorKind::ServerNotFound),
+        }
+    }
+
+    /// Whether the same call may succeed later without changing credentials or options
+    pub fn is_retryable(&self) -> bool {
+        matches!(
+            self.kind(),
+            ErrorKind::Network
+                | ErrorKind::Proxy
+                | ErrorKind::Timeout
+                | ErrorKind::ServerUnavailable
+        )
+    }
+
+    /// Text of error response, sent by server
+    pub fn response(&self) -> Option<&str> {
+        match self {
+            Error::AuthenticationFailed { response }
+            | Error::AccountLocked { response }
+            | Error::ServerUnavailable { response }
+            | Error::ImapRejected { response } => Some(response),
+            Error::Pop(err) => match err.kind() {
+                async_pop2::error::ErrorKind::ServerError(response) => Some(response),
+                _ => None,
+            },
+            Error::Attempt { source, .. } => source.response(),
+            Error::AllAttemptsFailed { attempts } => attempts.iter().find_map(Error::response),
+            _ => None,
+        }
+    }
+
+    /// Protocol and endpoint, which failed
+    pub fn attempt(&self) -> Option<(Protocol, &Endpoint)> {
+        match self {
+            Error::Attempt {
+                protocol, endpoint, ..
+            } => Some((*protocol, endpoint)),
+            _ => None,
+        }
+    }
+
+    /// OAuth2 tokens, which were refreshed before failure
+    ///
+    /// Refresh token may be rotated by provider, so it should be persisted even if login failed
+    pub fn refreshed_oauth(&self) -> Option<&OAuthData> {
+        match self {
+            Error::Attempt { oauth, .. } => oauth.as_deref(),
+            Error::AllAttemptsFailed { attempts } => {
+                attempts.iter().rev().find_map(Error::refreshed_oauth)
+            }
+            _ => None,
+        }
+    }
+
+    /// Attributes error of connection or login to `endpoint`
+    pub(crate) fn attempted(self, protocol: Protocol, endpoint: &Endpoint) -> Self {
+        Error::Attempt {
+            protocol,
+            endpoint: endpoint.clone(),
+            source: Box::new(self),
+            oauth: None,
+        }
+    }
+
+    /// Rejected login, classified by response codes of RFC 5530 (IMAP) and RFC 3206 (POP3),
+    /// falling back to wording of response
+    pub(crate) fn login_rejected(response: impl Into<String>) -> Self {
+        let response = response.into();
+        let lowercase = response.to_lowercase();
+        let mentions = |markers: &[&str]| markers.iter().any(|marker| lowercase.contains(marker));
+
+        if mentions(&["[unavailable]", "[sys/temp]", "[in-use]"]) {
+            Error::ServerUnavailable { response }
+        } else if mentions(&[
+            "[contactadmin]",
+            "locked",
+            "disabled",
+            "suspended",
+            "blocked",
+        ]) {
+            Error::AccountLocked { response }
+        } else {
+            Error::AuthenticationFailed { response }
+        }
+    }
+}
+
+/// Every attempt with its whole chain of causes, as `source` only fits a single one
+fn list_attempts(attempts: &[Error]) -> String {
+    let describe = |err: &Error| {
+        let mut description = err.to_string();
+        let mut source = std::error::Error::source(err);
+        while let Some(cause) = source {
+            description.push_str(&format!(": {cause}"));
+            source = cause.source();
+        }
+        description
+    };
+
+    attempts.iter().map(describe).collect::<Vec<_>>().join("; ")
+}
+
+/// Server text of `async_imap` NO/BAD error
+///
+/// async-imap keeps only its `Debug` form `code: .., info: Some("..")`, so escapes are undone here
+fn imap_response_text(response: &str) -> String {
+    let Some((_, info)) = response.split_once("info: Some(\"") else {
+        return response.to_owned();
+    };
+
+    let mut text = String::new();
+    let mut chars = info.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => return text,
+            '\\' => match chars.next() {
+                Some('n') => text.push('\n'),
+                Some('r') => text.push('\r'),
+                Some('t') => text.push('\t'),
+                Some('0') => text.push('\0'),
+                // `\u{1b}`
+                Some('u') => {
+                    let code: String = chars.by_ref().skip(1).take_while(|&c| c != '}').collect();
+                    text.extend(u32::from_str_radix(&code, 16).ok().and_then(char::from_u32));
+                }
+                Some(c) => text.push(c),
+                None => break,
+            },
+            c => text.push(c),
+        }
+    }
+
+    response.to_owned()
+}
+
+/// Read timeouts of connection surface as io errors deep inside protocol libraries
+fn is_timeout(err: &std::io::Error) -> bool {
+    err.kind() == std::io::ErrorKind::TimedOut
+}
+
+impl From<std::io::Error> for Error {
+    fn from(err: std::io::Error) -> Self {
+        match is_timeout(&err) {
+            true => Error::Timeout {
+                phase: TimeoutPhase::Command,
+            },
+            false => Error::Socket(err),
+        }
+    }
+}
+
+impl From<async_imap::error::Error> for Error {
+    fn from(err: async_imap::error::Error) -> Self {
+        match err {
+            async_imap::error::Error::Io(err) => err.into(),
+            async_imap::error::Error::No(response) | async_imap::error::Error::Bad(response) => {
+                Error::ImapRejected {
+                    response: imap_response_text(&response),
+                }
+            }
+            err => Error::Imap(err),
+        }
+    }
+}
+
+impl From<async_pop2::error::Error> for Error {
+    fn from(err: async_pop2::error::Error) -> Self {
+        match err.kind() {
+            async_pop2::error::ErrorKind::Io(io) if is_timeout(io) => Error::Timeout {
+                phase: TimeoutPhase::Command,
+            },
+            _ => Error::Pop(err),
+        }
+    }
+}
+
+/// Stage of connection, which took too long
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum TimeoutPhase {
+    /// TCP connect, including proxy handshake
+    Connect,
+    TlsHandshake,
+    /// Authentication, after connection was established
+    Login,
+    /// Waiting for response to a command
+    Command,
+}
+
+impl std::fmt::Display for TimeoutPhase {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let phase = match self {
+            TimeoutPhase::Connect => "connect",
+            TimeoutPhase::TlsHandshake => "tls handshake",
+            TimeoutPhase::Login => "login",
+            TimeoutPhase::Command => "command",
+        };
+        f.write_str(phase)
+    }
+}
+
+/// Limits of connection phases, `None` waits forever
+#[derive(Clone, Debug)]
+pub struct Timeouts {
+    pub connect: Option<Duration>,
+    pub tls_handshake: Option<Duration>,
+    pub login: Option<Duration>,
+    /// Longest silence of server while waiting for response.
+    /// It isn't applied to IMAP IDLE, where server stays silent until something happens
+    pub command: Option<Duration>,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            connect: Some(Duration::from_secs(30)),
+            tls_handshake: Some(Duration::from_secs(30)),
+            login: Some(Duration::from_secs(60)),
+            command: Some(Duration::from_secs(120)),
+        }
+    }
 }
 
 /// Dynamic email reader
@@ -89,47 +510,338 @@ pub trait DynEmailReader: Send {
     async fn dyn_get_filtered_emails(
         &mut self,
         filter: Box<dyn Filter>,
-    ) -> Result<Vec<OwnedMessage>, Error>;
+    ) -> Result<Vec<OwnedMessage>, Error> {
+        self.dyn_stream_filtered_emails(filter).try_collect().await
+    }
+
+    /// Stream emails, matching `filter`, one by one as soon as they are fetched
+    ///
+    /// Dropping the stream stops the crawl, so callers can bail out after the first match
+    fn dyn_stream_filtered_emails(
+        &mut self,
+        filter: Box<dyn Filter>,
+    ) -> BoxStream<'_, Result<OwnedMessage, Error>> {
+        self.dyn_stream_identified_emails(filter)
+            .map_ok(|(_, msg)| msg)
+            .boxed()
+    }
+
+    /// Same as `dyn_stream_filtered_emails`, but every email comes with its identifier
+    fn dyn_stream_identified_emails(
+        &mut self,
+        filter: Box<dyn Filter>,
+    ) -> BoxStream<'_, Result<IdentifiedMessage, Error>>;
+
+    /// Read emails, matching `filter`, which arrived after `state` was taken
+    ///
+    /// Returns matched emails together with advanced state, that should be persisted and passed to the next call.
+    /// Pass `SyncState::default()` to read the whole mailbox
+    async fn dyn_sync_filtered_emails(
+        &mut self,
+        filter: Box<dyn Filter>,
+        state: SyncState,
+    ) -> Result<(Vec<OwnedMessage>, SyncState), Error> {
+        let (found, state) = self.dyn_sync_identified_emails(filter, state).await?;
+        Ok((found.into_iter().map(|(_, msg)| msg).collect(), state))
+    }
+
+    /// Same as `dyn_sync_filtered_emails`, but every email comes with its identifier
+    async fn dyn_sync_identified_emails(
+        &mut self,
+        filter: Box<dyn Filter>,
+        state: SyncState,
+    ) -> Result<(Vec<IdentifiedMessage>, SyncState), Error>;
+
+    /// Wait for the first email, matching `filter`, which arrives after this call
+    ///
+    /// Uses IMAP IDLE where server supports it, otherwise polls mailbox every `poll_interval`.
+    /// Fails with `Error::WaitTimeout` once `timeout` passes.
+    ///
+    /// POP3 logs in again to poll, which forgets deletions, not committed with `dyn_commit`
+    async fn dyn_wait_for_email(
+        &mut self,
+        filter: Box<dyn Filter>,
+        timeout: Duration,
+        poll_interval: Duration,
+    ) -> Result<OwnedMessage, Error> {
+        let (_, msg) = self
+            .dyn_wait_for_identified_email(filter, timeout, poll_interval)
+            .await?;
+        Ok(msg)
+    }
+
+    /// Same as `dyn_wait_for_email`, but the email comes with its identifier
+    async fn dyn_wait_for_identified_email(
+        &mut self,
+        filter: Box<dyn Filter>,
+        timeout: Duration,
+        poll_interval: Duration,
+    ) -> Result<IdentifiedMessage, Error>;
+
+    /// Summaries of every message, listed without downloading their bodies
+    ///
+    /// POP3 servers without TOP don't let to download headers only, so whole messages are downloaded there
+    async fn dyn_list_envelopes(&mut self) -> Result<Vec<Envelope>, Error>;
+
+    /// Download the whole message, e.g. the one of `Envelope::id`
+    async fn dyn_fetch(&mut self, id: &MessageId) -> Result<OwnedMessage, Error>;
+
+    /// Set `flag` of message. Fails with `Error::Unsupported` on POP3, which has no flags
+    async fn dyn_flag(&mut self, id: &MessageId, flag: Flag) -> Result<(), Error>;
+
+    /// Clear `flag` of message. Fails with `Error::Unsupported` on POP3, which has no flags
+    async fn dyn_unflag(&mut self, id: &MessageId, flag: Flag) -> Result<(), Error>;
+
+    /// Mark message as read
+    async fn dyn_mark_seen(&mut self, id: &MessageId) -> Result<(), Error> {
+        self.dyn_flag(id, Flag::Seen).await
+    }
+
+    /// Copy message to `folder`. Fails with `Error::Unsupported` on POP3, which has no folders
+    async fn dyn_copy(&mut self, id: &MessageId, folder: &str) -> Result<(), Error>;
+
+    /// Move message to `folder`. Fails with `Error::Unsupported` on POP3, which has no folders
+    ///
+    /// Uses UID MOVE if server supports it, otherwise copies message and expunges the original.
+    /// Servers without UIDPLUS can't expunge a single message, so the original is removed by `dyn_commit`
+    async fn dyn_move(&mut self, id: &MessageId, folder: &str) -> Result<(), Error>;
+
+    /// Mark message for deletion
+    ///
+    /// Messages are removed by `dyn_commit`. Without it, IMAP leaves them flagged as `\Deleted`,
+    /// while POP3 server forgets deletions once connection is closed
+    async fn dyn_delete(&mut self, id: &MessageId) -> Result<(), Error>;
+
+    /// Remove messages, marked by `dyn_delete`
+    ///
+    /// POP3 has to log out for that, so it logs in again afterwards and message numbers may change
+    async fn dyn_commit(&mut self) -> Result<(), Error>;
+
+    /// Unmark messages, which were marked by `dyn_delete` since the last `dyn_commit`
+    async fn dyn_rollback(&mut self) -> Result<(), Error>;
+
+    /// Credentials, reader is connected with
+    ///
+    /// Differs from the ones passed to `connect_any` if OAuth2 tokens were refreshed, persist them in that case
+    fn dyn_mailbox(&self) -> &Mailbox;
+}
+
+/// Settings of connection, shared by all protocols
+#[derive(Clone, Default)]
+pub struct ConnectOptions {
+    /// Used to refresh expired or rejected OAuth2 access tokens. Without it, tokens are sent as is
+    pub token_refresher: Option<Arc<dyn TokenRefresher>>,
+
+    pub tls: TlsOptions,
+
+    /// Used to discover servers of domains, missing in `ServerMap`, by their MX and SRV records,
+    /// e.g. `dns::default_resolver()`. Without it, such domains fail with `Error::ServerNotFound`.
+    ///
+    /// Lookups don't go through proxy of mailbox, so its domain is revealed to the resolver
+    pub resolver: Option<Arc<dyn DnsResolver>>,
+
+    /// Add servers, discovered through DNS, to the global `ServerMap`, so they are looked up only once
+    pub cache_discovered: bool,
+
+    pub timeouts: Timeouts,
+
+    pub protocol_order: ProtocolOrder,
 }
 
 mod _obj_safety_guard {
     pub fn _test(_: Box<dyn super::DynEmailReader>) {}
 }
 
-/// Connect to any protocol in mailbox
+fn mailbox_domain(mailbox: &Mailbox) -> Result<&str, Error> {
+    mailbox.get_domain().ok_or(Error::MailboxInvalidDomain {
+        login: mailbox.email.clone(),
+    })
+}
+
+/// Endpoints of `domain`, found through DNS: entry of provider its MX records point to, or its SRV records
 ///
-/// Tries to extract `domain` and query it's endpoints in provided `ServerMap`
-/// Errors if `domain` is not present in `ServerMap`
-pub async fn connect_any(
+/// `by_mx` looks MX records up in the map, so shared map is locked only for that and not during lookups
+async fn discover_endpoints<F>(
+    domain: &str,
+    options: &ConnectOptions,
+    by_mx: impl FnOnce(Vec<dns::Mx>) -> F,
+) -> Result<Endpoints, Error>
+where
+    F: Future<Output = Option<Endpoints>>,
+{
+    let not_found = || Error::ServerNotFound {
+        domain: domain.to_owned(),
+    };
+    let Some(resolver) = &options.resolver else {
+        return Err(not_found());
+    };
+
+    let mx = resolver.mx(domain).await?;
+    if let Some(endpoints) = by_mx(mx).await {
+        return Ok(endpoints);
+    }
+
+    dns::discover_srv(domain, resolver.as_ref())
+        .await?
+        .ok_or_else(not_found)
+}
+
+/// Endpoints of `domain`: its own entry in `map`, otherwise the one discovered through DNS
+pub(crate) async fn find_endpoints(
+    domain: &str,
+    map: &ServerMap,
+    options: &ConnectOptions,
+) -> Result<Endpoints, Error> {
+    match map.get_by_domain(domain) {
+        Some(endpoints) => Ok(endpoints.clone()),
+        None => {
+            discover_endpoints(domain, options, |mx| {
+                std::future::ready(map.get_by_mx(&mx).cloned())
+            })
+            .await
+        }
+    }
+}
+
+/// Same as `find_endpoints`, but doesn't hold the lock during DNS lookups
+///
+/// Discovered endpoints are added to `map` if `ConnectOptions::cache_discovered` is set
+async fn find_endpoints_shared(
+    domain: &str,
+    map: &ArcMap,
+    options: &ConnectOptions,
+) -> Result<Endpoints, Error> {
+    if let Some(endpoints) = map.read().await.get_by_domain(domain) {
+        return Ok(endpoints.clone());
+    }
+
+    let endpoints = discover_endpoints(domain, options, |mx| async move {
+        map.read().await.get_by_mx(&mx).cloned()
+    })
+    .await?;
+    if options.cache_discovered {
+        map.write().await.add_server(Server {
+            domains: vec![domain.to_owned()],
+            endpoint: endpoints.clone(),
+        });
+    }
+
+    Ok(endpoints)
+}
+
+async fn connect_protocol(
+    protocol: Protocol,
     mailbox: Mailbox,
     proxy: Option<Proxy>,
-    map: &ServerMap,
+    entry: &Endpoints,
+    options: &ConnectOptions,
 ) -> Result<Box<dyn DynEmailReader>, Error> {
-    let domain = mailbox.get_domain().ok_or(Error::MailboxInvalidDomain {
-        login: mailbox.email.clone(),
-    })?;
-    let entry = map.get_by_domain(domain);
+    Ok(match protocol {
+        Protocol::Imap => {
+            let imap = entry.get_imap().expect("checked by caller");
+            Box::new(ImapConnector::connect(mailbox, imap, proxy, options).await?)
+        }
+        Protocol::Pop3 => {
+            let pop3 = entry.get_pop3().expect("checked by caller");
+            Box::new(Pop3Connector::connect(mailbox, pop3, proxy, options).await?)
+        }
+    })
+}
+
+/// Tries protocols of `entry` according to `ConnectOptions::protocol_order`
+///
+/// Fails with `Error::AllAttemptsFailed` if more than one endpoint was tried
+async fn connect_endpoints(
+    mailbox: Mailbox,
+    proxy: Option<Proxy>,
+    entry: &Endpoints,
+    options: &ConnectOptions,
+) -> Result<Box<dyn DynEmailReader>, Error> {
+    let protocols: Vec<Protocol> = options
+        .protocol_order
+        .protocols()
+        .iter()
+        .copied()
+        .filter(|protocol| match protocol {
+            Protocol::Imap => entry.get_imap().is_some(),
+            Protocol::Pop3 => entry.get_pop3().is_some(),
+        })
+        .collect();
 
-    let Some(entry) = entry else {
-        return Err(Error::ServerNotFound {
-            domain: domain.to_owned(),
+    // Entry lacks every protocol of the order, e.g. `ImapOnly` for POP3-only server
+    if protocols.is_empty() {
+        return Err(Error::AllAttemptsFailed {
+            attempts: vec![Error::ImapNotFound {
+                domain: mailbox_domain(&mailbox)?.to_owned(),
+            }],
         });
-    };
+    }
 
-    let mut err: Option<Error> = None;
-    if let Some(imap) = entry.get_imap() {
-        match ImapConnector::connect(mailbox.clone(), imap, proxy.clone()).await {
-            Ok(imap) => return Ok(Box::new(imap)),
-            Err(imap_err) => err = Some(imap_err.into()),
+    let connect =
+        |protocol| connect_protocol(protocol, mailbox.clone(), proxy.clone(), entry, options);
+
+    let mut attempts = Vec::new();
+    if options.protocol_order == ProtocolOrder::Race {
+        let mut racing: FuturesUnordered<_> = protocols
+            .into_iter()
+            .map(|protocol| {
+                let attempt = connect(protocol);
+                async move { (protocol, attempt.await) }
+            })
+            .collect();
+        while let Some((protocol, result)) = racing.next().await {
+            match result {
+                Ok(reader) => return Ok(reader),
+                Err(err) => {
+                    tracing::debug!(%protocol, %err, "connection attempt failed");
+                    attempts.push(err);
+                }
+            }
+        }
+    } else {
+        for protocol in protocols {
+            match connect(protocol).await {
+                Ok(reader) => return Ok(reader),
+                Err(err) => {
+                    tracing::debug!(%protocol, %err, "connection attempt failed");
+                    attempts.push(err);
+                }
+            }
         }
     }
 
-    if let Some(pop3) = entry.get_pop3() {
-        let conn = Pop3Connector::connect(mailbox.clone(), pop3, proxy.clone()).await?;
-        return Ok(Box::new(conn));
+    match attempts.len() {
+        1 => Err(attempts.remove(0)),
+        _ => Err(Error::AllAttemptsFailed { attempts }),
     }
+}
 
-    Err(err.unwrap())
+/// Connect to any protocol in mailbox
+///
+/// Tries to extract `domain` and query it's endpoints in provided `ServerMap`.
+/// If `domain` is not present there, looks for its mail provider by MX records,
+/// then for its own servers by SRV records (RFC 6186)
+/// Errors if none of them is found
+pub async fn connect_any(
+    mailbox: Mailbox,
+    proxy: Option<Proxy>,
+    map: &ServerMap,
+) -> Result<Box<dyn DynEmailReader>, Error> {
+    connect_any_with_options(mailbox, proxy, map, &ConnectOptions::default()).await
+}
+
+/// Connect to any protocol in mailbox with custom `ConnectOptions`
+///
+/// Protocols are tried in `ConnectOptions::protocol_order`, same as `connect_any` otherwise
+pub async fn connect_any_with_options(
+    mailbox: Mailbox,
+    proxy: Option<Proxy>,
+    map: &ServerMap,
+    options: &ConnectOptions,
+) -> Result<Box<dyn DynEmailReader>, Error> {
+    let entry = find_endpoints(mailbox_domain(&mailbox)?, map, options).await?;
+
+    connect_endpoints(mailbox, proxy, &entry, options).await
 }
 
 /// Connect to any protocol in mailbox using global ServerMap
@@ -140,10 +852,117 @@ pub async fn connect_any_global_map(
     mailbox: Mailbox,
     proxy: Option<Proxy>,
 ) -> Result<Box<dyn DynEmailReader>, Error> {
-    let map = ArcMap::global();
-    let read_lock = map.read().await;
+    connect_any_global_map_with_options(mailbox, proxy, &ConnectOptions::default()).await
+}
+
+/// Connect to any protocol in mailbox using global ServerMap and custom `ConnectOptions`
+///
+/// Global map isn't locked while connecting, so it can be updated meanwhile
+pub async fn connect_any_global_map_with_options(
+    mailbox: Mailbox,
+    proxy: Option<Proxy>,
+    options: &ConnectOptions,
+) -> Result<Box<dyn DynEmailReader>, Error> {
+    let entry =
+        find_endpoints_shared(mailbox_domain(&mailbox)?, &ArcMap::global(), options).await?;
 
-    connect_any(mailbox, proxy, &read_lock).await
+    connect_endpoints(mailbox, proxy, &entry, options).await
 }
 
 pub use filters::*;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_login_rejected_kind() {
+        let kind = |response: &str| Error::login_rejected(response).kind();
+
+        assert_eq!(
+            kind("[AUTHENTICATIONFAILED] Invalid credentials"),
+            ErrorKind::AuthenticationFailed
+        );
+        assert_eq!(
+            kind("[CONTACTADMIN] Account disabled"),
+            ErrorKind::AccountLocked
+        );
+        assert_eq!(
+            kind("[IN-USE] Mailbox is locked"),
+            ErrorKind::ServerUnavailable
+        );
+        assert!(Error::login_rejected("[SYS/TEMP] try later").is_retryable());
+
+        let imap = r#"code: None, info: Some("[AUTHENTICATIONFAILED] Invalid credentials")"#;
+        assert_eq!(
+            imap_response_text(imap),
+            "[AUTHENTICATIONFAILED] Invalid credentials"
+        );
+
+        let imap = r#"code: None, info: Some("say \"hi\" \\ \u{1b}ок")"#;
+        assert_eq!(imap_response_text(imap), "say \"hi\" \\ \u{1b}ок");
+        let err = Error::from(async_imap::error::Error::No(imap.to_owned()));
+        assert_eq!(err.response(), Some("say \"hi\" \\ \u{1b}ок"));
+    }
+
+    #[test]
+    fn test_token_refresh_kind() {
+        let endpoint_error = |status, body: &str| {
+            Error::TokenRefresh(
+                oauth::TokenEndpointError {
+                    status,
+                    body: body.to_owned(),
+                }
+                .into(),
+            )
+        };
+
+        assert_eq!(
+            endpoint_error(400, r#"{"error": "invalid_grant"}"#).kind(),
+            ErrorKind::AuthenticationFailed
+        );
+        assert!(endpoint_error(503, "").is_retryable());
+        assert!(endpoint_error(429, "").is_retryable());
+    }
+
+    #[test]
+    fn test_all_attempts_failed_kind() {
+        let attempts = Error::AllAttemptsFailed {
+            attempts: vec![
+                Error::Timeout {
+                    phase: TimeoutPhase::Connect,
+                },
+                Error::login_rejected("-ERR [AUTH] invalid password"),
+            ],
+        };
+        assert_eq!(attempts.kind(), ErrorKind::AuthenticationFailed);
+        assert!(attempts.to_string().contains("connect timed out; "));
+    }
+
+    #[tokio::test]
+    async fn test_missing_protocol() {
+        let mailbox = Mailbox {
+            email: "user@example.com".to_owned(),
+            password: String::new(),
+            oauth2: None,
+        };
+        let entry = Endpoints::Pop3 {
+            pop3: server_map::Pop3(server_map::ProtocolEndpoint {
+                domain: "pop.example.com".to_owned(),
+                port: 995,
+                security: server_map::Security::Tls,
+                auth: Vec::new(),
+            }),
+        };
+        let options = ConnectOptions {
+            protocol_order: ProtocolOrder::ImapOnly,
+            ..Default::default()
+        };
+
+        let Err(err) = connect_endpoints(mailbox, None, &entry, &options).await else {
+            panic!("pop3-only entry can't be connected with imap");
+        };
+        assert!(matches!(err, Error::AllAttemptsFailed { .. }));
+        assert_eq!(err.kind(), ErrorKind::ServerNotFound);
+    }
+}
@@ -0,0 +1,162 @@
+//! Identifiers of messages and their flags
+
+use chrono::{DateTime, Utc};
+use mail_parser::{Address, Message};
+use serde::{Deserialize, Serialize};
+
+use crate::Error;
+
+/// Identifier of message, returned together with it
+///
+/// Can be persisted and used by another reader of the same mailbox, as long as server keeps the message
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Hash)]
+#[serde(tag = "protocol", rename_all = "snake_case")]
+pub enum MessageId {
+    Imap {
+        folder: String,
+        uid_validity: u32,
+        uid: u32,
+    },
+    Pop3 {
+        /// Unique-id of message, `None` if server doesn't support UIDL
+        uidl: Option<String>,
+        /// Message number, used only if `uidl` is `None`. Numbers may change after relogin
+        number: usize,
+    },
+}
+
+/// Address from header of message
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct EmailAddress {
+    pub name: Option<String>,
+    pub email: Option<String>,
+}
+
+/// Summary of message, listed without downloading its body
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Envelope {
+    /// Pass it to `DynEmailReader::dyn_fetch` to download the whole message
+    pub id: MessageId,
+    pub from: Vec<EmailAddress>,
+    pub to: Vec<EmailAddress>,
+    pub subject: Option<String>,
+    /// Date of `Date:` header
+    pub date: Option<DateTime<Utc>>,
+    /// Size of the whole message in bytes
+    pub size: u64,
+    /// IMAP flags, e.g. `\Seen`. Always empty for POP3
+    pub flags: Vec<String>,
+}
+
+impl Envelope {
+    /// Envelope of message, whose headers were parsed into `headers`
+    pub(crate) fn from_headers(id: MessageId, size: u64, headers: &Message) -> Self {
+        Self {
+            id,
+            from: addresses(headers.from()),
+            to: addresses(headers.to()),
+            subject: headers.subject().map(str::to_owned),
+            date: header_date(headers),
+            size,
+            flags: Vec::new(),
+        }
+    }
+}
+
+fn addresses(address: Option<&Address>) -> Vec<EmailAddress> {
+    let Some(address) = address else {
+        return Vec::new();
+    };
+
+    address
+        .iter()
+        .map(|addr| EmailAddress {
+            name: addr.name.as_deref().map(str::to_owned),
+            email: addr.address.as_deref().map(str::to_owned),
+        })
+        .collect()
+}
+
+pub(crate) fn header_date(headers: &Message) -> Option<DateTime<Utc>> {
+    headers
+        .date()
+        .and_then(|date| DateTime::<Utc>::from_timestamp(date.to_timestamp(), 0))
+}
+
+/// Message flag
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Flag {
+    Seen,
+    Flagged,
+    Answered,
+    /// Custom IMAP keyword, e.g. `$Processed`
+    Keyword(String),
+}
+
+impl Flag {
+    /// Flag as IMAP atom
+    pub(crate) fn to_imap(&self) -> Result<&str, Error> {
+        Ok(match self {
+            Flag::Seen => "\\Seen",
+            Flag::Flagged => "\\Flagged",
+            Flag::Answered => "\\Answered",
+            Flag::Keyword(keyword) => {
+                let is_atom = !keyword.is_empty()
+                    && keyword.chars().all(|c| {
+                        c.is_ascii_graphic()
+                            && !matches!(c, '(' | ')' | '{' | '%' | '*' | '"' | '\\' | ']')
+                    });
+
+                if !is_atom {
+                    return Err(Error::InvalidKeyword {
+                        keyword: keyword.clone(),
+                    });
+                }
+                keyword
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_envelope_from_headers() {
+        let headers = b"From: =?utf-8?q?J=C3=B6rg?= <jorg@example.com>\r\n\
+            To: a@example.com, b@example.com\r\n\
+            Subject: Your code\r\n\
+            Date: Tue, 1 Jul 2003 10:52:37 +0200\r\n\r\n";
+        let headers = mail_parser::MessageParser::new()
+            .parse_headers(&headers[..])
+            .unwrap();
+
+        let id = MessageId::Pop3 {
+            uidl: None,
+            number: 1,
+        };
+        let envelope = Envelope::from_headers(id, 1024, &headers);
+
+        assert_eq!(envelope.from[0].name.as_deref(), Some("Jörg"));
+        assert_eq!(envelope.to.len(), 2);
+        assert_eq!(envelope.subject.as_deref(), Some("Your code"));
+        assert_eq!(
+            envelope.date.unwrap().to_rfc3339(),
+            "2003-07-01T08:52:37+00:00"
+        );
+    }
+
+    #[test]
+    fn test_flag_to_imap() {
+        assert_eq!(Flag::Seen.to_imap().unwrap(), "\\Seen");
+        assert_eq!(
+            Flag::Keyword("$Processed".to_owned()).to_imap().unwrap(),
+            "$Processed"
+        );
+        assert!(Flag::Keyword("a) UID EXPUNGE".to_owned())
+            .to_imap()
+            .is_err());
+        assert!(Flag::Keyword(String::new()).to_imap().is_err());
+    }
+}
@@ -0,0 +1,402 @@
+use std::future::Future;
+
+use chrono::{TimeDelta, Utc};
+
+use crate::{Endpoint, Error, ErrorKind, Mailbox, OAuthData, OAuthToken, Protocol};
+
+/// Tokens are refreshed this long before they actually expire
+const EXPIRATION_LEEWAY: TimeDelta = TimeDelta::seconds(60);
+
+impl OAuthToken {
+    /// Whether token is expired or about to expire
+    pub fn is_expired(&self) -> bool {
+        self.token_expiration - EXPIRATION_LEEWAY <= Utc::now()
+    }
+}
+
+/// Exchanges refresh token for a new access token
+///
+/// Connectors call it when access token is expired or rejected by server.
+/// Updated credentials are available through `DynEmailReader::dyn_mailbox`,
+/// or `Error::refreshed_oauth` if connection failed afterwards
+#[async_trait::async_trait]
+pub trait TokenRefresher: Send + Sync {
+    async fn refresh(&self, oauth: &OAuthData) -> Result<OAuthData, Error>;
+}
+
+/// Error response of OAuth2 token endpoint
+#[derive(thiserror::Error, Debug)]
+#[error("token endpoint responded with {status}: {body}")]
+pub struct TokenEndpointError {
+    pub status: u16,
+    pub body: String,
+}
+
+/// Kind of `Error::TokenRefresh`, so unreachable or overloaded token endpoint stays retryable
+pub(crate) fn refresh_error_kind(
+    err: &(dyn std::error::Error + Send + Sync + 'static),
+) -> ErrorKind {
+    if let Some(err) = err.downcast_ref::<reqwest::Error>() {
+        return if err.is_timeout() {
+            ErrorKind::Timeout
+        } else if err.is_connect() || err.is_request() || err.is_body() {
+            ErrorKind::Network
+        } else {
+            ErrorKind::Protocol
+        };
+    }
+
+    match err.downcast_ref::<TokenEndpointError>() {
+        Some(err) if err.status == 429 || err.status >= 500 => ErrorKind::ServerUnavailable,
+        // `invalid_grant` and the like: refresh token is revoked or expired
+        _ => ErrorKind::AuthenticationFailed,
+    }
+}
+
+/// Refreshes tokens with `refresh_token` grant of RFC 6749
+#[derive(Clone, Debug)]
+pub struct HttpTokenRefresher {
+    token_url: String,
+    client_id: String,
+    client_secret: Option<String>,
+    http: reqwest::Client,
+}
+
+#[derive(serde::Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    expires_in: i64,
+    /// Some providers rotate refresh token on every use
+    refresh_token: Option<String>,
+}
+
+impl HttpTokenRefresher {
+    /// Token endpoint, e.g. `https://oauth2.googleapis.com/token`
+    pub fn new(token_url: impl Into<String>, client_id: impl Into<String>) -> Self {
+        Self {
+            token_url: token_url.into(),
+            client_id: client_id.into(),
+            client_secret: None,
+            http: reqwest::Client::new(),
+        }
+    }
+
+    /// Secret of confidential clients. Public clients don't have one
+    pub fn with_client_secret(mut self, client_secret: impl Into<String>) -> Self {
+        self.client_secret = Some(client_secret.into());
+        self
+    }
+
+    /// Use custom http client, e.g. to route requests through proxy
+    pub fn with_http_client(mut self, http: reqwest::Client) -> Self {
+        self.http = http;
+        self
+    }
+}
+
+#[async_trait::async_trait]
+impl TokenRefresher for HttpTokenRefresher {
+    async fn refresh(&self, oauth: &OAuthData) -> Result<OAuthData, Error> {
+        let mut form = vec![
+            ("grant_type", "refresh_token"),
+            ("refresh_token", oauth.refresh.token.as_str()),
+            ("client_id", self.client_id.as_str()),
+        ];
+        if let Some(client_secret) = self.client_secret.as_deref() {
+            form.push(("client_secret", client_secret));
+        }
+
+        let response = self
+            .http
+            .post(&self.token_url)
+            .form(&form)
+            .send()
+            .await
+            .map_err(|err| Error::TokenRefresh(err.into()))?;
+
+        let status = response.status();
+        if !status.is_success() {
+            let body = response.text().await.unwrap_or_default();
+            return Err(Error::TokenRefresh(
+                TokenEndpointError {
+                    status: status.as_u16(),
+                    body,
+                }
+                .into(),
+            ));
+        }
+
+        let tokens: TokenResponse = response
+            .json()
+            .await
+            .map_err(|err| Error::TokenRefresh(err.into()))?;
+
+        let mut refresh = oauth.refresh.clone();
+        if let Some(token) = tokens.refresh_token {
+            refresh.token = token;
+        }
+
+        Ok(OAuthData {
+            access: OAuthToken {
+                token: tokens.access_token,
+                token_expiration: Utc::now() + TimeDelta::seconds(tokens.expires_in),
+            },
+            refresh,
+        })
+    }
+}
+
+/// Runs `login` with fresh access token
+///
+/// Token is refreshed beforehand if it's expired, and once more if server rejects it.
+/// `mailbox` is updated in place, so caller keeps the latest tokens. Errors are attributed
+/// to `endpoint` and carry refreshed tokens, if any, see `Error::refreshed_oauth`
+pub(crate) async fn login_with_refresh<T, F, Fut>(
+    mailbox: &mut Mailbox,
+    refresher: Option<&dyn TokenRefresher>,
+    protocol: Protocol,
+    endpoint: &Endpoint,
+    login: F,
+) -> Result<T, Error>
+where
+    F: FnMut(Mailbox) -> Fut,
+    Fut: Future<Output = Result<T, Error>>,
+{
+    let mut refreshed = false;
+    let result = refresh_and_login(mailbox, refresher, &mut refreshed, login).await;
+
+    result.map_err(|err| {
+        let mut err = err.attempted(protocol, endpoint);
+        if let Error::Attempt { oauth, .. } = &mut err {
+            *oauth = mailbox.oauth2.clone().filter(|_| refreshed).map(Box::new);
+        }
+        err
+    })
+}
+
+async fn refresh_and_login<T, F, Fut>(
+    mailbox: &mut Mailbox,
+    refresher: Option<&dyn TokenRefresher>,
+    refreshed: &mut bool,
+    mut login: F,
+) -> Result<T, Error>
+where
+    F: FnMut(Mailbox) -> Fut,
+    Fut: Future<Output = Result<T, Error>>,
+{
+    let (Some(refresher), Some(oauth)) = (refresher, mailbox.oauth2.as_mut()) else {
+        return login(mailbox.clone()).await;
+    };
+
+    if oauth.access.is_expired() {
+        *oauth = refresher.refresh(oauth).await?;
+        *refreshed = true;
+    }
+
+    match login(mailbox.clone()).await {
+        Err(err) if !*refreshed && err.kind() == ErrorKind::AuthenticationFailed => {
+            tracing::debug!(%err, "access token rejected, refreshing");
+
+            if let Some(oauth) = mailbox.oauth2.as_mut() {
+                *oauth = refresher.refresh(oauth).await?;
+                *refreshed = true;
+            }
+            login(mailbox.clone()).await
+        }
+        result => result,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use super::*;
+    use crate::server_map::{ProtocolEndpoint, Security};
+
+    /// Local token endpoint, answering requests with `responses` in order. Returns its url
+    /// and bodies of received requests
+    async fn token_endpoint(
+        responses: Vec<(u16, &'static str)>,
+    ) -> (String, Arc<Mutex<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/token", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(Vec::new()));
+
+        let received = requests.clone();
+        tokio::spawn(async move {
+            for (status, body) in responses {
+                let (mut stream, _) = listener.accept().await.unwrap();
+
+                let mut request = Vec::new();
+                let mut buf = [0; 4096];
+                let body_start = loop {
+                    let read = stream.read(&mut buf).await.unwrap();
+                    request.extend_from_slice(&buf[..read]);
+                    if let Some(end) = request.windows(4).position(|w| w == b"\r\n\r\n") {
+                        break end + 4;
+                    }
+                };
+                let head = String::from_utf8_lossy(&request[..body_start]).to_lowercase();
+                let length: usize = head
+                    .lines()
+                    .find_map(|line| line.strip_prefix("content-length:"))
+                    .map_or(0, |length| length.trim().parse().unwrap());
+                while request.len() < body_start + length {
+                    let read = stream.read(&mut buf).await.unwrap();
+                    request.extend_from_slice(&buf[..read]);
+                }
+                let request_body = String::from_utf8_lossy(&request[body_start..]).into_owned();
+                received.lock().unwrap().push(request_body);
+
+                let response = format!(
+                    "HTTP/1.1 {status} Stub\r\ncontent-type: application/json\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{body}",
+                    body.len()
+                );
+                stream.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+
+        (url, requests)
+    }
+
+    fn refresher(url: String) -> HttpTokenRefresher {
+        HttpTokenRefresher::new(url, "client")
+            .with_http_client(reqwest::Client::builder().no_proxy().build().unwrap())
+    }
+
+    fn mailbox(access_expiration: TimeDelta) -> Mailbox {
+        let token = |token: &str, expiration| OAuthToken {
+            token: token.to_owned(),
+            token_expiration: Utc::now() + expiration,
+        };
+        Mailbox {
+            email: "user@example.com".to_owned(),
+            password: String::new(),
+            oauth2: Some(OAuthData {
+                access: token("old-access", access_expiration),
+                refresh: token("old-refresh", TimeDelta::days(30)),
+            }),
+        }
+    }
+
+    fn endpoint() -> Endpoint {
+        ProtocolEndpoint {
+            domain: "imap.example.com".to_owned(),
+            port: 993,
+            security: Security::Tls,
+            auth: Vec::new(),
+        }
+    }
+
+    const ROTATED: &str =
+        r#"{"access_token":"new-access","expires_in":3600,"refresh_token":"new-refresh"}"#;
+
+    /// Login, accepting only `new-access` token
+    async fn login(mailbox: Mailbox) -> Result<String, Error> {
+        let access = mailbox.oauth2.unwrap().access.token;
+        match access.as_str() {
+            "new-access" => Ok(access),
+            _ => Err(Error::login_rejected(
+                "[AUTHENTICATIONFAILED] Invalid credentials",
+            )),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_refresh_expired() {
+        let (url, requests) = token_endpoint(vec![(200, ROTATED)]).await;
+        let refresher = refresher(url);
+        let mut mailbox = mailbox(TimeDelta::zero());
+
+        let token = login_with_refresh(
+            &mut mailbox,
+            Some(&refresher),
+            Protocol::Imap,
+            &endpoint(),
+            login,
+        )
+        .await
+        .unwrap();
+        assert_eq!(token, "new-access");
+
+        let oauth = mailbox.oauth2.unwrap();
+        assert_eq!(oauth.refresh.token, "new-refresh");
+        assert!(!oauth.access.is_expired());
+
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 1);
+        assert!(requests[0].contains("grant_type=refresh_token"));
+        assert!(requests[0].contains("refresh_token=old-refresh"));
+    }
+
+    #[tokio::test]
+    async fn test_refresh_rejected() {
+        let (url, requests) = token_endpoint(vec![(200, ROTATED)]).await;
+        let refresher = refresher(url);
+        let mut mailbox = mailbox(TimeDelta::hours(1));
+
+        let token = login_with_refresh(
+            &mut mailbox,
+            Some(&refresher),
+            Protocol::Imap,
+            &endpoint(),
+            login,
+        )
+        .await
+        .unwrap();
+        assert_eq!(token, "new-access");
+        assert_eq!(requests.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_refresh_then_rejected() {
+        let (url, _) = token_endpoint(vec![(200, ROTATED)]).await;
+        let refresher = refresher(url);
+        let mut mailbox = mailbox(TimeDelta::zero());
+
+        let err = login_with_refresh(
+            &mut mailbox,
+            Some(&refresher),
+            Protocol::Imap,
+            &endpoint(),
+            |_| async { Err::<(), _>(Error::login_rejected("[AUTHENTICATIONFAILED] Nope")) },
+        )
+        .await
+        .unwrap_err();
+
+        assert_eq!(err.kind(), ErrorKind::AuthenticationFailed);
+        assert_eq!(
+            err.attempt().map(|(protocol, _)| protocol),
+            Some(Protocol::Imap)
+        );
+        let oauth = err.refreshed_oauth().expect("refreshed tokens are kept");
+        assert_eq!(oauth.refresh.token, "new-refresh");
+    }
+
+    #[tokio::test]
+    async fn test_refresh_failed() {
+        let (url, _) = token_endpoint(vec![(503, r#"{"error":"unavailable"}"#)]).await;
+        let refresher = refresher(url);
+        let mut mailbox = mailbox(TimeDelta::zero());
+
+        let err = login_with_refresh(
+            &mut mailbox,
+            Some(&refresher),
+            Protocol::Imap,
+            &endpoint(),
+            login,
+        )
+        .await
+        .unwrap_err();
+
+        assert_eq!(err.kind(), ErrorKind::ServerUnavailable);
+        assert!(err.refreshed_oauth().is_none());
+        assert_eq!(mailbox.oauth2.unwrap().refresh.token, "old-refresh");
+    }
+}
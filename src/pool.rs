@@ -0,0 +1,405 @@
+//! Checking many mailboxes at once
+
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex},
+};
+
+use futures::{stream::FuturesUnordered, Stream, StreamExt};
+use proxied::Proxy;
+use tokio::sync::Semaphore;
+
+use crate::{
+    connect_endpoints, find_endpoints_shared, mailbox_domain, server_map::ArcMap, ConnectOptions,
+    Error, Filter, Mailbox, OwnedMessage,
+};
+
+type DomainLimits = Mutex<HashMap<String, Arc<Semaphore>>>;
+
+/// Mailboxes of busy domains, set aside by `check_many`, per its concurrency
+const DEFERRED_PER_SLOT: usize = 4;
+
+/// Limits of `MailboxPool`
+#[derive(Clone, Debug)]
+pub struct PoolOptions {
+    /// Mailboxes checked at once
+    pub concurrency: usize,
+    /// Mailboxes of the same mail domain checked at once, `None` for no limit.
+    /// Providers throttle or block addresses, which open too many connections
+    pub per_domain: Option<usize>,
+}
+
+impl Default for PoolOptions {
+    fn default() -> Self {
+        Self {
+            concurrency: 16,
+            per_domain: Some(4),
+        }
+    }
+}
+
+/// Checks batches of mailboxes with bounded parallelism
+///
+/// Limits are shared by every call on the same pool
+pub struct MailboxPool {
+    map: ArcMap,
+    connect_options: ConnectOptions,
+    per_domain: Option<usize>,
+    concurrency: usize,
+    connections: Arc<Semaphore>,
+    domains: DomainLimits,
+}
+
+/// Limit of a mail domain, forgotten once no mailbox of the domain is checked
+struct DomainLimit<'a> {
+    domains: &'a DomainLimits,
+    domain: String,
+    semaphore: Arc<Semaphore>,
+}
+
+impl Drop for DomainLimit<'_> {
+    fn drop(&mut self) {
+        let mut domains = self.domains.lock().expect("poisoned");
+        // Clones are made under the same lock, so only the map and this one are left
+        if Arc::strong_count(&self.semaphore) == 2 {
+            domains.remove(&self.domain);
+        }
+    }
+}
+
+impl MailboxPool {
+    pub fn new(map: ArcMap, connect_options: ConnectOptions, options: PoolOptions) -> Self {
+        Self {
+            map,
+            connect_options,
+            per_domain: options.per_domain,
+            concurrency: options.concurrency.max(1),
+            connections: Arc::new(Semaphore::new(options.concurrency.max(1))),
+            domains: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Pool over global `ServerMap`
+    pub fn global(connect_options: ConnectOptions, options: PoolOptions) -> Self {
+        Self::new(ArcMap::global(), connect_options, options)
+    }
+
+    fn domain_limit(&self, domain: &str) -> Option<DomainLimit<'_>> {
+        let limit = self.per_domain?;
+        let domain = domain.to_lowercase();
+        let mut domains = self.domains.lock().expect("poisoned");
+
+        let semaphore = domains
+            .entry(domain.clone())
+            .or_insert_with(|| Arc::new(Semaphore::new(limit.max(1))))
+            .clone();
+        Some(DomainLimit {
+            domains: &self.domains,
+            domain,
+            semaphore,
+        })
+    }
+
+    /// Read emails, matching `filter`, from a single mailbox within limits of the pool
+    ///
+    /// Returns mailbox with refreshed OAuth2 tokens, if they were refreshed
+    pub async fn check(
+        &self,
+        mut mailbox: Mailbox,
+        proxy: Option<Proxy>,
+        filter: Box<dyn Filter>,
+    ) -> (Mailbox, Result<Vec<OwnedMessage>, Error>) {
+        let domain = match mailbox_domain(&mailbox) {
+            Ok(domain) => domain.to_owned(),
+            Err(err) => return (mailbox, Err(err)),
+        };
+
+        // Domain permit goes first, so mailboxes of a busy domain don't hold global ones while waiting
+        let domain_limit = self.domain_limit(&domain);
+        let _domain_permit = match &domain_limit {
+            Some(limit) => Some(limit.semaphore.acquire().await.expect("never closed")),
+            None => None,
+        };
+        let _permit = self.connections.acquire().await.expect("never closed");
+
+        let result = async {
+            let entry = find_endpoints_shared(&domain, &self.map, &self.connect_options).await?;
+            let mut reader =
+                connect_endpoints(mailbox.clone(), proxy, &entry, &self.connect_options).await?;
+
+            let messages = reader.dyn_get_filtered_emails(filter).await;
+            mailbox = reader.dyn_mailbox().clone();
+            messages
+        }
+        .await;
+
+        if let Some(oauth) = result.as_ref().err().and_then(Error::refreshed_oauth) {
+            mailbox.oauth2 = Some(oauth.clone());
+        }
+
+        (mailbox, result)
+    }
+
+    /// Read emails, matching `filter`, from every mailbox, yielding results as soon as they complete
+    ///
+    /// Mailboxes are taken from `mailboxes` as previous ones complete, at most `PoolOptions::concurrency` at once.
+    /// Mailboxes of domains at their `PoolOptions::per_domain` limit are set aside, so other domains
+    /// go ahead of them. Up to 4 mailboxes per concurrency slot are set aside, then input waits
+    pub fn check_many<'a>(
+        &'a self,
+        mailboxes: impl IntoIterator<Item = (Mailbox, Option<Proxy>)> + 'a,
+        filter: impl Filter + 'static,
+    ) -> impl Stream<Item = (Mailbox, Result<Vec<OwnedMessage>, Error>)> + 'a {
+        let filter: Arc<dyn Filter> = Arc::new(filter);
+        let mut scheduler = Scheduler::new(
+            mailboxes,
+            self.per_domain,
+            self.concurrency * DEFERRED_PER_SLOT,
+        );
+
+        async_stream::stream! {
+            let mut running = FuturesUnordered::new();
+
+            loop {
+                while running.len() < self.concurrency {
+                    let Some((mailbox, proxy)) = scheduler.next() else {
+                        break;
+                    };
+                    let domain = domain_key(&mailbox);
+                    let check = self.check(mailbox, proxy, Box::new(filter.clone()));
+                    running.push(async move { (domain, check.await) });
+                }
+
+                let Some((domain, result)) = running.next().await else {
+                    break;
+                };
+                scheduler.finished(domain);
+                yield result;
+            }
+        }
+    }
+}
+
+fn domain_key(mailbox: &Mailbox) -> Option<String> {
+    mailbox_domain(mailbox).ok().map(str::to_lowercase)
+}
+
+/// Order of `check_many`, which keeps mailboxes of domains at their limit aside
+struct Scheduler<I> {
+    input: std::iter::Fuse<I>,
+    per_domain: Option<usize>,
+    /// Mailboxes being checked, per domain
+    running: HashMap<String, usize>,
+    deferred: VecDeque<(Mailbox, Option<Proxy>)>,
+    max_deferred: usize,
+}
+
+impl<I: Iterator<Item = (Mailbox, Option<Proxy>)>> Scheduler<I> {
+    fn new(
+        input: impl IntoIterator<IntoIter = I>,
+        per_domain: Option<usize>,
+        max_deferred: usize,
+    ) -> Self {
+        Self {
+            input: input.into_iter().fuse(),
+            per_domain,
+            running: HashMap::new(),
+            deferred: VecDeque::new(),
+            max_deferred,
+        }
+    }
+
+    fn has_room(&self, mailbox: &Mailbox) -> bool {
+        match (self.per_domain, domain_key(mailbox)) {
+            (Some(limit), Some(domain)) => {
+                self.running.get(&domain).copied().unwrap_or(0) < limit.max(1)
+            }
+            _ => true,
+        }
+    }
+
+    /// Mailbox to check next. `None` if there are none or all of them wait for their domains
+    fn next(&mut self) -> Option<(Mailbox, Option<Proxy>)> {
+        let ready = self
+            .deferred
+            .iter()
+            .position(|(mailbox, _)| self.has_room(mailbox));
+        let next = match ready {
+            Some(at) => self.deferred.remove(at),
+            None => loop {
+                if self.deferred.len() >= self.max_deferred {
+                    return None;
+                }
+
+                let next = self.input.next()?;
+                if self.has_room(&next.0) {
+                    break Some(next);
+                }
+                self.deferred.push_back(next);
+            },
+        }?;
+
+        if let Some(domain) = domain_key(&next.0) {
+            *self.running.entry(domain).or_default() += 1;
+        }
+        Some(next)
+    }
+
+    fn finished(&mut self, domain: Option<String>) {
+        let Some(domain) = domain else {
+            return;
+        };
+        if let Some(running) = self.running.get_mut(&domain) {
+            *running -= 1;
+            if *running == 0 {
+                self.running.remove(&domain);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::atomic::{AtomicUsize, Ordering},
+        time::Duration,
+    };
+
+    use tokio::{io::AsyncWriteExt, net::TcpListener};
+
+    use super::*;
+    use crate::server_map::{Endpoints, Pop3, ProtocolEndpoint, Security, Server};
+
+    /// Connections, open at the same time, and the most of them seen
+    #[derive(Default)]
+    struct Gauge {
+        open: AtomicUsize,
+        max: AtomicUsize,
+    }
+
+    impl Gauge {
+        fn enter(&self) {
+            let open = self.open.fetch_add(1, Ordering::SeqCst) + 1;
+            self.max.fetch_max(open, Ordering::SeqCst);
+        }
+
+        fn leave(&self) {
+            self.open.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+
+    /// POP3 server, which holds every connection for a while and then rejects it
+    async fn pop3_stub(domain: Arc<Gauge>, total: Arc<Gauge>) -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let (domain, total) = (domain.clone(), total.clone());
+
+                tokio::spawn(async move {
+                    domain.enter();
+                    total.enter();
+                    tokio::time::sleep(Duration::from_millis(30)).await;
+                    // Left before replying, so the next connection isn't counted together with this one
+                    domain.leave();
+                    total.leave();
+                    let _ = stream.write_all(b"-ERR busy\r\n").await;
+                });
+            }
+        });
+
+        port
+    }
+
+    fn server(domain: &str, port: u16) -> Server {
+        Server {
+            domains: vec![domain.to_owned()],
+            endpoint: Endpoints::Pop3 {
+                pop3: Pop3(ProtocolEndpoint {
+                    domain: "127.0.0.1".to_owned(),
+                    port,
+                    security: Security::Plain,
+                    auth: Vec::new(),
+                }),
+            },
+        }
+    }
+
+    #[tokio::test]
+    async fn test_limits() {
+        let total = Arc::new(Gauge::default());
+        let (a, b) = (Arc::new(Gauge::default()), Arc::new(Gauge::default()));
+
+        let map = ArcMap::new();
+        {
+            let mut map = map.write().await;
+            map.add_server(server("a.test", pop3_stub(a.clone(), total.clone()).await));
+            map.add_server(server("b.test", pop3_stub(b.clone(), total.clone()).await));
+        }
+
+        let options = PoolOptions {
+            concurrency: 3,
+            per_domain: Some(2),
+        };
+        let pool = MailboxPool::new(map, ConnectOptions::default(), options);
+
+        let mailboxes = (0..12).map(|i| {
+            let domain = if i % 3 == 0 { "b.test" } else { "a.test" };
+            let mailbox = Mailbox {
+                email: format!("user{i}@{domain}"),
+                password: String::new(),
+                oauth2: None,
+            };
+            (mailbox, None)
+        });
+        let results: Vec<_> = pool.check_many(mailboxes, ()).collect().await;
+
+        assert_eq!(results.len(), 12);
+        assert!(results.iter().all(|(_, result)| result.is_err()));
+
+        assert!(total.max.load(Ordering::SeqCst) <= 3);
+        assert!(a.max.load(Ordering::SeqCst) <= 2);
+        assert!(b.max.load(Ordering::SeqCst) <= 2);
+
+        // Limits of domains are dropped once their mailboxes are checked
+        assert!(pool.domains.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_scheduler() {
+        let mailbox = |email: &str| {
+            let mailbox = Mailbox {
+                email: email.to_owned(),
+                password: String::new(),
+                oauth2: None,
+            };
+            (mailbox, None)
+        };
+        let input = ["1@a.test", "2@A.test", "3@a.test", "4@b.test", "5@a.test"].map(mailbox);
+        let mut scheduler = Scheduler::new(input, Some(1), 3);
+        let mut next = || scheduler.next().map(|(mailbox, _)| mailbox.email);
+
+        // Busy domain doesn't hold up the others
+        assert_eq!(next().as_deref(), Some("1@a.test"));
+        assert_eq!(next().as_deref(), Some("4@b.test"));
+        // Set aside mailboxes are at their limit, so input waits
+        assert_eq!(next(), None);
+
+        scheduler.finished(Some("a.test".to_owned()));
+        assert_eq!(scheduler.next().unwrap().0.email, "2@A.test");
+        assert!(scheduler.next().is_none());
+
+        scheduler.finished(Some("a.test".to_owned()));
+        scheduler.finished(Some("b.test".to_owned()));
+        assert_eq!(scheduler.next().unwrap().0.email, "3@a.test");
+        assert!(scheduler.next().is_none());
+
+        scheduler.finished(Some("a.test".to_owned()));
+        assert_eq!(scheduler.next().unwrap().0.email, "5@a.test");
+        scheduler.finished(Some("a.test".to_owned()));
+        assert!(scheduler.next().is_none());
+        assert!(scheduler.running.is_empty());
+    }
+}
@@ -1,86 +1,637 @@
-use async_pop2::response::types::DataType;
+use std::collections::{HashMap, HashSet};
+
+use async_pop2::{
+    error::ErrorKind,
+    response::{capability::Capability, list::ListResponse, types::DataType, uidl::UidlResponse},
+};
+use async_stream::try_stream;
+use futures::{stream::BoxStream, Stream, StreamExt};
+use server_map::Security;
+use tokio::time::Instant;
 
 use super::*;
 
 pub struct Pop3 {
     client: async_pop2::Client<Box<dyn Conn>>,
     mailbox_info: Mailbox,
+    endpoint: server_map::Pop3,
+    proxy: Option<Proxy>,
+    options: ConnectOptions,
+}
+
+/// Messages, which were already in the maildrop when `wait_for_email` started
+enum Seen {
+    Uidl(SyncState),
+    /// Servers without UIDL only let us assume that new messages are appended to the end
+    Count(usize),
 }
 
 impl Pop3 {
-    async fn get_filtered_emails(
+    /// Maildrop is locked for the whole session, so new messages only become visible after relogin
+    ///
+    /// Deletions, not committed with `dyn_commit`, are forgotten as if connection was closed,
+    /// instead of being committed by QUIT. Connection is dropped without QUIT if RSET fails
+    async fn reconnect(&mut self) -> Result<(), Error> {
+        if self.client.rset().await.is_ok() {
+            self.client.quit().await.ok();
+        }
+        self.relogin().await
+    }
+
+    /// Logs in again after QUIT
+    async fn relogin(&mut self) -> Result<(), Error> {
+        self.client = Pop3Connector::connect_client(
+            &mut self.mailbox_info,
+            &self.endpoint,
+            self.proxy.clone(),
+            &self.options,
+        )
+        .await?;
+
+        Ok(())
+    }
+
+    async fn retr_message(&mut self, msg_id: usize) -> Result<OwnedMessage, Error> {
+        let bytes = self.client.retr(msg_id).await?;
+
+        let new_msg = MessageParser::new()
+            .parse(bytes.as_ref())
+            .ok_or(Error::MessageParseFailed)?
+            .into_owned();
+
+        Ok(new_msg)
+    }
+
+    /// Message numbers with their unique-ids
+    async fn unique_ids(&mut self) -> Result<Vec<(usize, String)>, Error> {
+        let UidlResponse::Multiple(uidl) = self.client.uidl(None).await? else {
+            return Err(async_pop2::error::Error::new(
+                ErrorKind::UnexpectedResponse,
+                "Expected multi-line uidl response",
+            )
+            .into());
+        };
+
+        uidl.items()
+            .iter()
+            .map(|unique_id| Ok((unique_id.index().value()?, unique_id.id().value()?)))
+            .collect()
+    }
+
+    /// Unique-ids by message numbers, empty if server doesn't support UIDL
+    async fn unique_ids_by_number(&mut self) -> Result<HashMap<usize, String>, Error> {
+        if !self.client.has_capability([Capability::Uidl]) {
+            return Ok(HashMap::new());
+        }
+
+        Ok(self.unique_ids().await?.into_iter().collect())
+    }
+
+    fn stream_filtered_emails<'a>(
+        &'a mut self,
+        filter: impl Filter + 'a,
+    ) -> impl Stream<Item = Result<IdentifiedMessage, Error>> + Send + 'a {
+        try_stream! {
+            let mut unique_ids = self.unique_ids_by_number().await?;
+
+            let stat = self.client.stat().await?;
+            let total_msg_count = stat.counter().value()?;
+
+            for curr_msg_id in 1..=total_msg_count {
+                let new_msg = self.retr_message(curr_msg_id).await?;
+
+                if filter.filter(&new_msg) {
+                    let id = MessageId::Pop3 {
+                        uidl: unique_ids.remove(&curr_msg_id),
+                        number: curr_msg_id,
+                    };
+                    yield (id, new_msg);
+                }
+            }
+        }
+    }
+
+    async fn list_envelopes(&mut self) -> Result<Vec<Envelope>, Error> {
+        let ListResponse::Multiple(list) = self.client.list(None).await? else {
+            return Err(async_pop2::error::Error::new(
+                ErrorKind::UnexpectedResponse,
+                "Expected multi-line list response",
+            )
+            .into());
+        };
+        let sizes = list
+            .items()
+            .iter()
+            .map(|stat| Ok((stat.counter().value()?, stat.size().value()? as u64)))
+            .collect::<Result<Vec<(usize, u64)>, Error>>()?;
+
+        let mut unique_ids = self.unique_ids_by_number().await?;
+
+        let supports_top = self.client.has_capability([Capability::Top]);
+        if !supports_top {
+            tracing::warn!("pop3 server doesn't support TOP, downloading whole messages");
+        }
+
+        let parser = MessageParser::new();
+        let mut envelopes = Vec::with_capacity(sizes.len());
+        for (number, size) in sizes {
+            let bytes = match supports_top {
+                true => self.client.top(number, 0).await?,
+                false => self.client.retr(number).await?,
+            };
+            let headers = parser
+                .parse_headers(bytes.as_ref())
+                .ok_or(Error::MessageParseFailed)?;
+
+            let id = MessageId::Pop3 {
+                uidl: unique_ids.remove(&number),
+                number,
+            };
+            envelopes.push(Envelope::from_headers(id, size, &headers));
+        }
+
+        Ok(envelopes)
+    }
+
+    /// Current number of message `id`
+    async fn message_number(&mut self, id: &MessageId) -> Result<usize, Error> {
+        let not_found = || Error::MessageNotFound { id: id.clone() };
+
+        match id {
+            MessageId::Pop3 {
+                uidl: Some(uidl), ..
+            } => self
+                .unique_ids()
+                .await?
+                .into_iter()
+                .find(|(_, unique_id)| unique_id == uidl)
+                .map(|(number, _)| number)
+                .ok_or_else(not_found),
+            MessageId::Pop3 { uidl: None, number } => Ok(*number),
+            MessageId::Imap { .. } => Err(not_found()),
+        }
+    }
+
+    /// Deletions are only applied on QUIT
+    async fn commit(&mut self) -> Result<(), Error> {
+        self.client.quit().await?;
+        self.relogin().await
+    }
+
+    async fn sync_filtered_emails(
         &mut self,
         filter: impl Filter,
-    ) -> Result<Vec<OwnedMessage>, Error> {
-        let stat = self.client.stat().await?;
-        let total_msg_count = stat.counter().value()?;
-        let mut result = Vec::new();
-        if total_msg_count < 1 {
-            return Ok(result);
+        mut state: SyncState,
+    ) -> Result<(Vec<IdentifiedMessage>, SyncState), Error> {
+        if !self.client.has_capability([Capability::Uidl]) {
+            tracing::warn!("pop3 server doesn't support UIDL, falling back to full fetch");
+
+            let found = self.stream_filtered_emails(filter).try_collect().await?;
+            return Ok((found, state));
+        }
+
+        let mut found = Vec::new();
+        let mut seen = HashSet::new();
+
+        for (msg_id, uid) in self.unique_ids().await? {
+            if !state.pop3.contains(&uid) {
+                let new_msg = self.retr_message(msg_id).await?;
+
+                if filter.filter(&new_msg) {
+                    let id = MessageId::Pop3 {
+                        uidl: Some(uid.clone()),
+                        number: msg_id,
+                    };
+                    found.push((id, new_msg));
+                }
+            }
+
+            seen.insert(uid);
         }
 
-        for curr_msg_id in 1..=total_msg_count {
-            let bytes = self.client.retr(curr_msg_id).await?.to_vec();
+        // UIDs of messages that left the maildrop are forgotten
+        state.pop3 = seen;
+
+        Ok((found, state))
+    }
+
+    async fn seen_now(&mut self) -> Result<Seen, Error> {
+        if self.client.has_capability([Capability::Uidl]) {
+            let state = SyncState {
+                pop3: self
+                    .unique_ids()
+                    .await?
+                    .into_iter()
+                    .map(|(_, uid)| uid)
+                    .collect(),
+                ..Default::default()
+            };
+
+            return Ok(Seen::Uidl(state));
+        }
+
+        let stat = self.client.stat().await?;
+        Ok(Seen::Count(stat.counter().value()?))
+    }
+
+    async fn find_new_email(
+        &mut self,
+        filter: &impl Filter,
+        seen: &mut Seen,
+    ) -> Result<Option<IdentifiedMessage>, Error> {
+        match seen {
+            Seen::Uidl(state) => {
+                let (found, new_state) = self
+                    .sync_filtered_emails(filter, std::mem::take(state))
+                    .await?;
+                *state = new_state;
+
+                Ok(found.into_iter().next())
+            }
+            Seen::Count(count) => {
+                let total_msg_count = self.client.stat().await?.counter().value()?;
 
-            let parser = MessageParser::new();
-            let new_msg = parser
-                .parse(bytes.as_slice())
-                .ok_or(Error::MessageParseFailed)?
-                .into_owned();
+                for msg_id in (*count + 1)..=total_msg_count {
+                    let new_msg = self.retr_message(msg_id).await?;
+                    if filter.filter(&new_msg) {
+                        let id = MessageId::Pop3 {
+                            uidl: None,
+                            number: msg_id,
+                        };
+                        return Ok(Some((id, new_msg)));
+                    }
+                }
+                *count = total_msg_count;
 
-            if filter.filter(&new_msg) {
-                result.push(new_msg)
+                Ok(None)
             }
         }
+    }
+
+    async fn wait_for_email(
+        &mut self,
+        filter: impl Filter,
+        timeout: Duration,
+        poll_interval: Duration,
+    ) -> Result<IdentifiedMessage, Error> {
+        let deadline = Instant::now() + timeout;
+        let mut seen = self.seen_now().await?;
 
-        Ok(result)
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Err(Error::WaitTimeout);
+            }
+            tokio::time::sleep(poll_interval.min(remaining)).await;
+
+            self.reconnect().await?;
+            if let Some(msg) = self.find_new_email(&filter, &mut seen).await? {
+                return Ok(msg);
+            }
+        }
     }
 }
 
 #[async_trait::async_trait]
 impl DynEmailReader for Pop3 {
-    async fn dyn_get_filtered_emails(
+    fn dyn_stream_identified_emails(
         &mut self,
         filter: Box<dyn Filter>,
-    ) -> Result<Vec<OwnedMessage>, Error> {
-        self.get_filtered_emails(filter).await
+    ) -> BoxStream<'_, Result<IdentifiedMessage, Error>> {
+        self.stream_filtered_emails(filter).boxed()
+    }
+
+    async fn dyn_sync_identified_emails(
+        &mut self,
+        filter: Box<dyn Filter>,
+        state: SyncState,
+    ) -> Result<(Vec<IdentifiedMessage>, SyncState), Error> {
+        self.sync_filtered_emails(filter, state).await
+    }
+
+    async fn dyn_wait_for_identified_email(
+        &mut self,
+        filter: Box<dyn Filter>,
+        timeout: Duration,
+        poll_interval: Duration,
+    ) -> Result<IdentifiedMessage, Error> {
+        self.wait_for_email(filter, timeout, poll_interval).await
+    }
+
+    async fn dyn_list_envelopes(&mut self) -> Result<Vec<Envelope>, Error> {
+        self.list_envelopes().await
+    }
+
+    async fn dyn_fetch(&mut self, id: &MessageId) -> Result<OwnedMessage, Error> {
+        let number = self.message_number(id).await?;
+        self.retr_message(number).await
+    }
+
+    async fn dyn_flag(&mut self, _: &MessageId, _: Flag) -> Result<(), Error> {
+        Err(Error::Unsupported {
+            operation: "flags",
+            protocol: Protocol::Pop3,
+        })
+    }
+
+    async fn dyn_unflag(&mut self, _: &MessageId, _: Flag) -> Result<(), Error> {
+        Err(Error::Unsupported {
+            operation: "flags",
+            protocol: Protocol::Pop3,
+        })
+    }
+
+    async fn dyn_copy(&mut self, _: &MessageId, _: &str) -> Result<(), Error> {
+        Err(Error::Unsupported {
+            operation: "copy",
+            protocol: Protocol::Pop3,
+        })
+    }
+
+    async fn dyn_move(&mut self, _: &MessageId, _: &str) -> Result<(), Error> {
+        Err(Error::Unsupported {
+            operation: "move",
+            protocol: Protocol::Pop3,
+        })
+    }
+
+    async fn dyn_delete(&mut self, id: &MessageId) -> Result<(), Error> {
+        let number = self.message_number(id).await?;
+        self.client.dele(number).await?;
+
+        Ok(())
+    }
+
+    async fn dyn_commit(&mut self) -> Result<(), Error> {
+        self.commit().await
+    }
+
+    async fn dyn_rollback(&mut self) -> Result<(), Error> {
+        self.client.rset().await?;
+
+        Ok(())
+    }
+
+    fn dyn_mailbox(&self) -> &Mailbox {
+        &self.mailbox_info
     }
 }
 
 pub struct Pop3Connector;
 impl Pop3Connector {
     pub async fn connect(
-        mailbox: Mailbox,
-        server_map::Pop3(endpoint): &server_map::Pop3,
+        mut mailbox: Mailbox,
+        endpoint: &server_map::Pop3,
         proxy: Option<Proxy>,
+        options: &ConnectOptions,
     ) -> Result<Pop3, Error> {
-        let stream = common::connect_maybe_proxied_stream_tls(
+        let client = Self::connect_client(&mut mailbox, endpoint, proxy.clone(), options).await?;
+
+        Ok(Pop3 {
+            client,
+            mailbox_info: mailbox,
+            endpoint: endpoint.clone(),
+            proxy,
+            options: options.clone(),
+        })
+    }
+
+    /// Connects and logs in, refreshing OAuth2 tokens of `mailbox` if needed
+    async fn connect_client(
+        mailbox: &mut Mailbox,
+        endpoint: &server_map::Pop3,
+        proxy: Option<Proxy>,
+        options: &ConnectOptions,
+    ) -> Result<async_pop2::Client<Box<dyn Conn>>, Error> {
+        oauth::login_with_refresh(
+            mailbox,
+            options.token_refresher.as_deref(),
+            Protocol::Pop3,
+            &endpoint.0,
+            |mailbox| Self::login(mailbox, endpoint, proxy.clone(), options),
+        )
+        .await
+    }
+
+    /// Unauthenticated client, secured according to `endpoint.security`
+    async fn connect_unauthenticated(
+        endpoint: &server_map::Endpoint,
+        proxy: Option<Proxy>,
+        options: &ConnectOptions,
+    ) -> Result<async_pop2::Client<Box<dyn Conn>>, Error> {
+        let ConnectOptions { tls, timeouts, .. } = options;
+        let stream = common::connect_maybe_proxied_stream(
             endpoint.domain.clone(),
             endpoint.port,
-            proxy.clone(),
+            proxy,
+            timeouts,
+            common::ReadTimeout::new(timeouts.command),
         )
         .await?;
 
-        let mut client = async_pop2::new(stream).await?;
-
-        match mailbox.oauth2.as_ref() {
-            None => {
-                client.login(&mailbox.email, &mailbox.password).await?;
+        let mut client = match endpoint.security {
+            Security::Tls => {
+                let stream =
+                    common::upgrade_tls(endpoint.domain.clone(), stream, tls, timeouts).await?;
+                return Ok(async_pop2::new(stream).await?);
             }
-            Some(oauth) => {
-                let authorizer = async_pop2::sasl::OAuth2Authenticator::new(
-                    &mailbox.email,
-                    oauth.access.token.clone(),
-                );
-                client.auth(authorizer).await?;
+            Security::Plain => return Ok(async_pop2::new(stream).await?),
+            Security::StartTls | Security::StartTlsOpportunistic => async_pop2::new(stream).await?,
+        };
+
+        let advertised = client.has_capability([Capability::Stls]);
+        // Empty capabilities mean CAPA failed, such servers may still support STLS
+        let blind = !advertised
+            && endpoint.security == Security::StartTls
+            && client.capabilities().is_empty();
+
+        if !advertised && !blind {
+            if endpoint.security == Security::StartTls {
+                return Err(Error::StartTlsUnsupported {
+                    domain: endpoint.domain.clone(),
+                });
             }
+
+            tracing::warn!(
+                domain = endpoint.domain,
+                "pop3 server doesn't support STLS, staying in plaintext"
+            );
+            return Ok(client);
+        }
+
+        if let Err(err) = client.stls().await {
+            return Err(match err.kind() {
+                ErrorKind::ServerError(_) if blind => Error::StartTlsUnsupported {
+                    domain: endpoint.domain.clone(),
+                },
+                _ => err.into(),
+            });
+        }
+        let stream = client.into_stream().ok_or_else(|| {
+            async_pop2::error::Error::new(ErrorKind::NotConnected, "Connection lost after STLS")
+        })?;
+        let stream = common::upgrade_tls(endpoint.domain.clone(), stream, tls, timeouts).await?;
+
+        Ok(async_pop2::new_after_stls(stream).await?)
+    }
+
+    async fn login(
+        mailbox: Mailbox,
+        server_map::Pop3(endpoint): &server_map::Pop3,
+        proxy: Option<Proxy>,
+        options: &ConnectOptions,
+    ) -> Result<async_pop2::Client<Box<dyn Conn>>, Error> {
+        let mut client = Self::connect_unauthenticated(endpoint, proxy, options).await?;
+
+        let authenticate = async {
+            let result = match mailbox.oauth2.as_ref() {
+                None => client
+                    .login(&mailbox.email, &mailbox.password)
+                    .await
+                    .map(drop),
+                Some(oauth) => {
+                    let authorizer = async_pop2::sasl::OAuth2Authenticator::new(
+                        &mailbox.email,
+                        oauth.access.token.clone(),
+                    );
+                    client.auth(authorizer).await.map(drop)
+                }
+            };
+
+            result.map_err(|err| match err.kind() {
+                ErrorKind::ServerError(response) => Error::login_rejected(response.as_str()),
+                _ => err.into(),
+            })
         };
+        common::with_timeout(TimeoutPhase::Login, options.timeouts.login, authenticate).await?;
 
-        Ok(Pop3 {
-            client,
-            mailbox_info: mailbox,
-        })
+        Ok(client)
     }
 }
 
 pub use filters::*;
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use tokio::{
+        io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+        net::TcpListener,
+    };
+
+    use super::*;
+
+    /// POP3 server, answering every command with `reply`. Returns its port and received commands
+    async fn pop3_stub(reply: fn(&str) -> &'static str) -> (u16, Arc<Mutex<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let commands = Arc::new(Mutex::new(Vec::new()));
+
+        let received = commands.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let received = received.clone();
+                tokio::spawn(async move {
+                    let (reader, mut writer) = stream.into_split();
+                    writer.write_all(b"+OK ready\r\n").await.unwrap();
+
+                    let mut lines = BufReader::new(reader).lines();
+                    while let Ok(Some(line)) = lines.next_line().await {
+                        let response = format!("{}\r\n", reply(&line));
+                        let quit = line == "QUIT";
+                        received.lock().unwrap().push(line);
+                        writer.write_all(response.as_bytes()).await.unwrap();
+                        if quit {
+                            break;
+                        }
+                    }
+                });
+            }
+        });
+
+        (port, commands)
+    }
+
+    /// POP3 server without CAPA, which rejects STLS
+    async fn server_without_capa() -> (u16, Arc<Mutex<Vec<String>>>) {
+        pop3_stub(|command| match command {
+            "STLS" => "-ERR not now",
+            _ => "-ERR unknown command",
+        })
+        .await
+    }
+
+    fn endpoint(port: u16, security: Security) -> server_map::Endpoint {
+        server_map::ProtocolEndpoint {
+            domain: "127.0.0.1".to_owned(),
+            port,
+            security,
+            auth: Vec::new(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_stls_without_capa() {
+        let (port, commands) = server_without_capa().await;
+        let endpoint = endpoint(port, Security::StartTls);
+
+        let result =
+            Pop3Connector::connect_unauthenticated(&endpoint, None, &ConnectOptions::default())
+                .await;
+        assert!(matches!(result, Err(Error::StartTlsUnsupported { .. })));
+        assert!(commands
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|command| command == "STLS"));
+    }
+
+    #[tokio::test]
+    async fn test_opportunistic_without_capa() {
+        let (port, commands) = server_without_capa().await;
+        let endpoint = endpoint(port, Security::StartTlsOpportunistic);
+
+        let result =
+            Pop3Connector::connect_unauthenticated(&endpoint, None, &ConnectOptions::default())
+                .await;
+        assert!(result.is_ok());
+        assert!(!commands
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|command| command == "STLS"));
+    }
+
+    #[tokio::test]
+    async fn test_reconnect_forgets_deletions() {
+        let (port, commands) = pop3_stub(|command| match command {
+            "CAPA" => "-ERR unknown command",
+            _ => "+OK done",
+        })
+        .await;
+        let mailbox = Mailbox {
+            email: "user@example.com".to_owned(),
+            password: "secret".to_owned(),
+            oauth2: None,
+        };
+        let endpoint = server_map::Pop3(endpoint(port, Security::Plain));
+
+        let mut pop3 = Pop3Connector::connect(mailbox, &endpoint, None, &ConnectOptions::default())
+            .await
+            .unwrap();
+        pop3.client.dele(1).await.unwrap();
+        pop3.reconnect().await.unwrap();
+
+        // QUIT would commit the deletion, so it's rolled back first
+        let commands = commands.lock().unwrap();
+        let quit = commands
+            .iter()
+            .position(|command| command == "QUIT")
+            .unwrap();
+        assert_eq!(commands[quit - 1], "RSET");
+        assert!(commands[quit + 1..]
+            .iter()
+            .any(|command| command == "USER user@example.com"));
+    }
+}
@@ -1,17 +1,52 @@
 use std::{
     borrow::Cow,
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     ops::{Deref, DerefMut},
-    sync::{Arc, LazyLock},
+    path::{Path, PathBuf},
+    sync::{Arc, LazyLock, Mutex},
 };
 
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use tokio::sync::RwLock;
 
+/// How connection to endpoint is secured
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum Security {
+    /// Implicit TLS, e.g. ports 993 and 995
+    #[default]
+    Tls,
+    /// Plaintext connection upgraded with STARTTLS/STLS, fails if server doesn't offer it
+    StartTls,
+    /// Same as `StartTls`, but stays in plaintext if server doesn't offer it
+    StartTlsOpportunistic,
+    /// No encryption at all. Only for local test servers
+    Plain,
+}
+
+/// Authentication method, advertised for endpoint
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum AuthMethod {
+    /// LOGIN/PLAIN with password as is
+    PasswordCleartext,
+    /// Challenge-response, e.g. CRAM-MD5
+    PasswordEncrypted,
+    OAuth2,
+    ClientCertificate,
+    #[serde(other)]
+    Other,
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Hash)]
 pub struct ProtocolEndpoint {
     pub domain: String,
     pub port: u16,
+    #[serde(default)]
+    pub security: Security,
+    /// Supported authentication methods in order of preference. Empty if unknown
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub auth: Vec<AuthMethod>,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Hash)]
@@ -26,11 +61,12 @@ pub type Endpoint = ProtocolEndpoint;
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Hash)]
 #[serde(untagged)]
 pub enum Endpoints {
+    // Untagged variants are tried in order, so `Full` goes first not to be read as `Pop3`
+    Full { pop3: Pop3, imap: Imap },
+
     Pop3 { pop3: Pop3 },
 
     Imap { imap: Imap },
-
-    Full { pop3: Pop3, imap: Imap },
 }
 
 impl Endpoints {
@@ -54,42 +90,175 @@ pub type Domain = String;
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Hash)]
 pub struct Server {
+    /// Exact domains or patterns
+    ///
+    /// `*.example.com` matches any subdomain of `example.com`, but not `example.com` itself.
+    /// `*` in any other label matches exactly one label, e.g. `mail.*.example.com`
     pub domains: Vec<Domain>,
     #[serde(flatten)]
     pub endpoint: Endpoints,
 }
 
+#[derive(thiserror::Error, Debug)]
+pub enum ServerMapError {
+    #[error("couldn't access server map file {path}")]
+    Io {
+        path: PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
+
+    #[error("malformed server map")]
+    Json(#[from] serde_json::Error),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Label {
+    Any,
+    Exact(String),
+}
+
+/// Domain with wildcards, see `Server::domains`
+#[derive(Clone, Debug)]
+struct DomainPattern {
+    raw: Domain,
+    /// Starts with `*.`, stripped from `labels`
+    any_subdomain: bool,
+    labels: Vec<Label>,
+}
+
+impl DomainPattern {
+    fn parse(raw: &str) -> Option<Self> {
+        if !raw.contains('*') {
+            return None;
+        }
+
+        let (any_subdomain, rest) = match raw.strip_prefix("*.") {
+            Some(rest) => (true, rest),
+            None => (false, raw),
+        };
+        let labels = rest
+            .split('.')
+            .map(|label| match label {
+                "*" => Label::Any,
+                label => Label::Exact(label.to_lowercase()),
+            })
+            .collect();
+
+        Some(Self {
+            raw: raw.to_owned(),
+            any_subdomain,
+            labels,
+        })
+    }
+
+    fn matches(&self, domain: &str) -> bool {
+        let domain: Vec<&str> = domain.split('.').collect();
+
+        let suffix = match self.any_subdomain {
+            true if domain.len() > self.labels.len() => &domain[domain.len() - self.labels.len()..],
+            false if domain.len() == self.labels.len() => &domain[..],
+            _ => return false,
+        };
+
+        self.labels
+            .iter()
+            .zip(suffix)
+            .all(|(pattern, label)| match pattern {
+                Label::Any => true,
+                Label::Exact(pattern) => pattern.eq_ignore_ascii_case(label),
+            })
+    }
+
+    /// Patterns with more fixed labels win, fixed depth beats any subdomain
+    fn specificity(&self) -> (usize, bool) {
+        let exact = self
+            .labels
+            .iter()
+            .filter(|label| matches!(label, Label::Exact(_)))
+            .count();
+
+        (exact, !self.any_subdomain)
+    }
+}
+
+/// Every endpoint ever added to any map, see `ServerMap::add_server`
+static ENDPOINTS: LazyLock<Mutex<HashSet<&'static Endpoints>>> = LazyLock::new(Default::default);
+
+fn intern(endpoint: Endpoints) -> &'static Endpoints {
+    let mut endpoints = ENDPOINTS.lock().unwrap_or_else(|err| err.into_inner());
+    if let Some(existing) = endpoints.get(&endpoint) {
+        return existing;
+    }
+
+    let endpoint = Box::leak(Box::new(endpoint));
+    endpoints.insert(endpoint);
+    endpoint
+}
+
+/// Serialized as a list of `Server`, the same one `servers()` returns
 pub struct ServerMap {
     map: HashMap<Domain, &'static Endpoints>,
+    patterns: Vec<(DomainPattern, &'static Endpoints)>,
 }
 
 impl ServerMap {
     pub fn new() -> Self {
         Self {
             map: HashMap::new(),
+            patterns: Vec::new(),
         }
     }
 
+    /// Endpoints are leaked, so `get_by_domain` can hand out `'static` references
+    ///
+    /// Equal endpoints share one allocation across all maps, so reloading the same config
+    /// doesn't leak again. Memory still grows with every distinct endpoint ever added
     pub fn add_server(&mut self, server: Server) {
-        let endpoint = Box::leak(Box::new(server.endpoint));
+        let endpoint = intern(server.endpoint);
         for domain in server.domains {
-            self.map.insert(domain, endpoint);
+            // Domains are case-insensitive, lookups lowercase them too
+            let domain = domain.to_lowercase();
+            let Some(pattern) = DomainPattern::parse(&domain) else {
+                self.map.insert(domain, endpoint);
+                continue;
+            };
+
+            self.patterns
+                .retain(|(existing, _)| existing.raw != pattern.raw);
+            self.patterns.push((pattern, endpoint));
         }
     }
 
+    /// Exact entry of `domain`, otherwise the most specific matching pattern
     pub fn get_by_domain<'this>(
         &'this self,
         domain: impl Into<String>,
     ) -> Option<&'static Endpoints> {
-        self.map.get(&domain.into()).map(|d| *d)
+        let domain = domain.into().to_lowercase();
+        if let Some(endpoints) = self.map.get(&domain) {
+            return Some(*endpoints);
+        }
+
+        self.patterns
+            .iter()
+            .filter(|(pattern, _)| pattern.matches(&domain))
+            // `max_by_key` returns the last of equal ones, reversed to prefer the earliest added
+            .rev()
+            .max_by_key(|(pattern, _)| pattern.specificity())
+            .map(|(_, endpoints)| *endpoints)
     }
 
     pub fn servers(&self) -> Vec<Server> {
         let map = self.map.clone();
+        let patterns = self
+            .patterns
+            .iter()
+            .map(|(pattern, endpoint)| (pattern.raw.clone(), *endpoint));
 
         let mut reversed_map: HashMap<Endpoints, Server> = HashMap::new();
 
-        for (domain, endpoint) in map.into_iter() {
+        for (domain, endpoint) in map.into_iter().chain(patterns) {
             let entry = reversed_map.entry(endpoint.clone());
 
             entry
@@ -100,7 +269,69 @@ impl ServerMap {
                 });
         }
 
-        reversed_map.into_iter().map(|(_, server)| server).collect()
+        let mut servers: Vec<Server> = reversed_map.into_values().collect();
+
+        // Stable order keeps saved files diffable
+        for server in &mut servers {
+            server.domains.sort_unstable();
+        }
+        servers.sort_unstable_by(|a, b| a.domains.cmp(&b.domains));
+
+        servers
+    }
+
+    pub fn from_json(json: &str) -> Result<Self, serde_json::Error> {
+        serde_json::from_str(json)
+    }
+
+    pub fn to_json(&self) -> Result<String, serde_json::Error> {
+        serde_json::to_string_pretty(self)
+    }
+
+    pub fn from_path(path: impl AsRef<Path>) -> Result<Self, ServerMapError> {
+        let path = path.as_ref();
+        let json = std::fs::read_to_string(path).map_err(|source| ServerMapError::Io {
+            path: path.to_owned(),
+            source,
+        })?;
+
+        Ok(Self::from_json(&json)?)
+    }
+
+    /// Written to a temporary file next to `path` first, so crash never leaves it truncated
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), ServerMapError> {
+        let path = path.as_ref();
+        let json = self.to_json()?;
+
+        let mut temp = path.as_os_str().to_owned();
+        temp.push(".tmp");
+        let temp = PathBuf::from(temp);
+
+        let result = std::fs::write(&temp, json).and_then(|()| std::fs::rename(&temp, path));
+        result.map_err(|source| {
+            let _ = std::fs::remove_file(&temp);
+            ServerMapError::Io {
+                path: path.to_owned(),
+                source,
+            }
+        })
+    }
+}
+
+impl Serialize for ServerMap {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.servers().serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for ServerMap {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let mut map = ServerMap::new();
+        for server in Vec::<Server>::deserialize(deserializer)? {
+            map.add_server(server);
+        }
+
+        Ok(map)
     }
 }
 
@@ -128,4 +359,111 @@ impl ArcMap {
     pub async fn write(&self) -> impl DerefMut<Target = ServerMap> {
         self.inner.clone().write_owned().await
     }
+
+    /// Replace whole map at once, readers see either the old or the new one
+    pub async fn replace(&self, map: ServerMap) {
+        *self.write().await = map;
+    }
+
+    /// Replace whole map with the one from file
+    ///
+    /// File is parsed before taking the lock, so on error the current map stays untouched.
+    /// Unchanged endpoints are reused, only new ones are leaked (see `ServerMap::add_server`)
+    pub async fn reload_from_path(&self, path: impl AsRef<Path>) -> Result<(), ServerMapError> {
+        let map = ServerMap::from_path(path)?;
+        self.replace(map).await;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pop3_server(domains: &[&str], host: &str) -> Server {
+        Server {
+            domains: domains.iter().map(|domain| domain.to_string()).collect(),
+            endpoint: Endpoints::Pop3 {
+                pop3: Pop3(ProtocolEndpoint {
+                    domain: host.to_owned(),
+                    port: 995,
+                    security: Security::Tls,
+                    auth: Vec::new(),
+                }),
+            },
+        }
+    }
+
+    fn host(map: &ServerMap, domain: &str) -> Option<String> {
+        let endpoints = map.get_by_domain(domain)?;
+        Some(endpoints.get_pop3()?.0.domain.clone())
+    }
+
+    #[test]
+    fn test_wildcard_lookup() {
+        let mut map = ServerMap::new();
+        map.add_server(pop3_server(&["*.example.com"], "any"));
+        map.add_server(pop3_server(&["*.eu.example.com"], "eu"));
+        map.add_server(pop3_server(&["mail.*.example.com"], "mail"));
+        map.add_server(pop3_server(&["exact.eu.example.com"], "exact"));
+
+        assert_eq!(host(&map, "example.com"), None);
+        assert_eq!(host(&map, "a.b.example.com").as_deref(), Some("any"));
+        assert_eq!(host(&map, "x.eu.example.com").as_deref(), Some("eu"));
+        assert_eq!(host(&map, "mail.us.example.com").as_deref(), Some("mail"));
+        assert_eq!(host(&map, "exact.eu.example.com").as_deref(), Some("exact"));
+        assert_eq!(map.servers().len(), 4);
+    }
+
+    #[test]
+    fn test_case_insensitive_lookup() {
+        let mut map = ServerMap::new();
+        map.add_server(pop3_server(&["*.COM"], "wildcard"));
+        map.add_server(pop3_server(&["Gmail.com"], "exact"));
+
+        assert_eq!(host(&map, "gmail.com").as_deref(), Some("exact"));
+        assert_eq!(host(&map, "GMAIL.COM").as_deref(), Some("exact"));
+        assert_eq!(host(&map, "Mail.Example.com").as_deref(), Some("wildcard"));
+        assert_eq!(map.servers()[0].domains, ["*.com"]);
+    }
+
+    #[test]
+    fn test_json_round_trip() {
+        let mut map = ServerMap::new();
+        map.add_server(pop3_server(&["example.com", "example.org"], "pop"));
+        map.add_server(pop3_server(&["*.example.net"], "wildcard"));
+        map.add_server(Server {
+            domains: vec!["full.example.com".to_owned()],
+            endpoint: Endpoints::Full {
+                pop3: Pop3(ProtocolEndpoint {
+                    domain: "pop.full.example.com".to_owned(),
+                    port: 110,
+                    security: Security::StartTls,
+                    auth: vec![AuthMethod::PasswordCleartext],
+                }),
+                imap: Imap(ProtocolEndpoint {
+                    domain: "imap.full.example.com".to_owned(),
+                    port: 993,
+                    security: Security::Tls,
+                    auth: vec![AuthMethod::OAuth2],
+                }),
+            },
+        });
+
+        let json = map.to_json().unwrap();
+        let parsed = ServerMap::from_json(&json).unwrap();
+        assert_eq!(parsed.servers(), map.servers());
+        assert_eq!(parsed.to_json().unwrap(), json);
+
+        // Reloading the same config reuses already leaked endpoints
+        let endpoint = |map: &ServerMap| map.get_by_domain("example.com").unwrap() as *const _;
+        assert_eq!(endpoint(&parsed), endpoint(&map));
+
+        let path = std::env::temp_dir().join(format!("server-map-{}.json", std::process::id()));
+        map.save(&path).unwrap();
+        let loaded = ServerMap::from_path(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.servers(), map.servers());
+    }
 }
@@ -0,0 +1,108 @@
+use std::collections::{HashMap, HashSet};
+
+use serde::{Deserialize, Serialize};
+
+/// Position of a single IMAP folder at the moment of the last sync
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct FolderCursor {
+    /// UIDVALIDITY of the folder. If the server reports another value, cursor is discarded
+    pub uid_validity: u32,
+    /// Highest UID that was already seen in this folder
+    pub last_uid: u32,
+}
+
+impl FolderCursor {
+    /// Cursor of `folder` to continue from, starting over if UIDVALIDITY has changed
+    pub(crate) fn resume(state: &SyncState, folder: &str, uid_validity: u32) -> Self {
+        Self {
+            uid_validity,
+            last_uid: state.imap_last_uid(folder, uid_validity).unwrap_or(0),
+        }
+    }
+
+    /// UID range of messages, which arrived after cursor, `None` if there are none
+    ///
+    /// Moves cursor to the end of range, if it's known from UIDNEXT.
+    /// Note that `n:*` always matches the last message, even if its UID is lower than `n`
+    pub(crate) fn next_range(&mut self, uid_next: Option<u32>) -> Option<String> {
+        let first = self.last_uid + 1;
+        match uid_next {
+            Some(next) if next <= first => None,
+            Some(next) => {
+                self.last_uid = next - 1;
+                Some(format!("{first}:{}", next - 1))
+            }
+            None => Some(format!("{first}:*")),
+        }
+    }
+
+    /// Moves cursor past fetched `uid`
+    pub(crate) fn advance(&mut self, uid: u32) {
+        self.last_uid = self.last_uid.max(uid);
+    }
+}
+
+/// Persisted cursor of a mailbox
+///
+/// Returned by `DynEmailReader::dyn_sync_filtered_emails` and passed back to the next call,
+/// so only messages which arrived in between are downloaded.
+/// `SyncState::default()` means "nothing was seen yet" and fetches the whole mailbox
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct SyncState {
+    /// IMAP cursors, keyed by folder name
+    #[serde(default)]
+    pub imap: HashMap<String, FolderCursor>,
+
+    /// POP3 unique-ids (UIDL) of already seen messages
+    #[serde(default)]
+    pub pop3: HashSet<String>,
+}
+
+impl SyncState {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Cursor of IMAP `folder`, if it's still valid for `uid_validity`
+    pub fn imap_last_uid(&self, folder: &str, uid_validity: u32) -> Option<u32> {
+        self.imap
+            .get(folder)
+            .filter(|cursor| cursor.uid_validity == uid_validity)
+            .map(|cursor| cursor.last_uid)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_folder_cursor() {
+        let mut state = SyncState::new();
+        state.imap.insert(
+            "INBOX".to_owned(),
+            FolderCursor {
+                uid_validity: 7,
+                last_uid: 10,
+            },
+        );
+
+        let mut cursor = FolderCursor::resume(&state, "INBOX", 7);
+        assert_eq!(cursor.last_uid, 10);
+        assert_eq!(cursor.next_range(Some(11)), None);
+        assert_eq!(cursor.next_range(Some(15)).as_deref(), Some("11:14"));
+        assert_eq!(cursor.last_uid, 14);
+
+        let mut cursor = FolderCursor::resume(&state, "INBOX", 7);
+        assert_eq!(cursor.next_range(None).as_deref(), Some("11:*"));
+        cursor.advance(13);
+        cursor.advance(12);
+        assert_eq!(cursor.last_uid, 13);
+
+        // UIDVALIDITY changed, so old UIDs mean nothing
+        let mut cursor = FolderCursor::resume(&state, "INBOX", 8);
+        assert_eq!(cursor.uid_validity, 8);
+        assert_eq!(cursor.next_range(None).as_deref(), Some("1:*"));
+        assert_eq!(FolderCursor::resume(&state, "Spam", 7).last_uid, 0);
+    }
+}
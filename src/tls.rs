@@ -0,0 +1,377 @@
+use std::{collections::HashMap, sync::Arc};
+
+use rustls::{
+    client::{
+        danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+        WebPkiServerVerifier,
+    },
+    crypto::CryptoProvider,
+    pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer, ServerName, UnixTime},
+    CertificateError, ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme,
+};
+use sha2::{Digest, Sha256};
+use tokio_rustls::TlsConnector;
+
+use crate::Error;
+
+/// SHA-256 of certificate's DER-encoded SubjectPublicKeyInfo
+pub type SpkiPin = [u8; 32];
+
+/// Client certificate, presented to servers which require mutual TLS
+#[derive(Clone, Debug)]
+pub struct ClientAuth {
+    pub chain: Vec<CertificateDer<'static>>,
+    pub key: Arc<PrivateKeyDer<'static>>,
+}
+
+/// TLS settings, used for implicit TLS as well as STARTTLS
+///
+/// `TlsOptions::default()` trusts webpki roots only, like browsers do
+#[derive(Clone, Debug, Default)]
+pub struct TlsOptions {
+    /// Trusted in addition to webpki roots, or instead of them if `replace_roots` is set
+    pub roots: Vec<CertificateDer<'static>>,
+    pub replace_roots: bool,
+
+    /// Allowed SPKI hashes, keyed by endpoint domain. Server certificate must match one of them
+    pub pins: HashMap<String, Vec<SpkiPin>>,
+
+    pub client_auth: Option<ClientAuth>,
+
+    /// Names sent in SNI and checked against certificate instead of endpoint domain, keyed by it
+    pub sni: HashMap<String, String>,
+
+    /// Accept any certificate chain, including self-signed and expired ones. Pins are still checked
+    ///
+    /// Connection can be intercepted by anyone on the way, use only with trusted test servers
+    pub dangerous_accept_invalid_certs: bool,
+}
+
+impl TlsOptions {
+    /// Trust certificates from `pem` in addition to webpki roots
+    pub fn with_extra_roots_pem(mut self, pem: &[u8]) -> Result<Self, Error> {
+        self.roots.extend(parse_certificates(pem)?);
+        Ok(self)
+    }
+
+    /// Trust only certificates from `pem`
+    pub fn with_roots_pem(mut self, pem: &[u8]) -> Result<Self, Error> {
+        self.roots = parse_certificates(pem)?;
+        self.replace_roots = true;
+        Ok(self)
+    }
+
+    pub fn with_pin(mut self, domain: impl Into<String>, pin: SpkiPin) -> Self {
+        self.pins.entry(domain.into()).or_default().push(pin);
+        self
+    }
+
+    /// Present certificate chain and private key from PEM to servers
+    pub fn with_client_auth_pem(mut self, chain_pem: &[u8], key_pem: &[u8]) -> Result<Self, Error> {
+        let key =
+            PrivateKeyDer::from_pem_slice(key_pem).map_err(|err| Error::TlsConfig(err.into()))?;
+
+        self.client_auth = Some(ClientAuth {
+            chain: parse_certificates(chain_pem)?,
+            key: Arc::new(key),
+        });
+        Ok(self)
+    }
+
+    /// Use `sni` instead of `domain` for endpoint at `domain`
+    pub fn with_sni(mut self, domain: impl Into<String>, sni: impl Into<String>) -> Self {
+        self.sni.insert(domain.into(), sni.into());
+        self
+    }
+
+    /// See `dangerous_accept_invalid_certs` field
+    pub fn dangerous_accept_invalid_certs(mut self) -> Self {
+        self.dangerous_accept_invalid_certs = true;
+        self
+    }
+
+    /// Connector for endpoint at `domain` and the name to verify its certificate against
+    pub(crate) fn connector(
+        &self,
+        domain: &str,
+    ) -> Result<(TlsConnector, ServerName<'static>), Error> {
+        let name = self.sni.get(domain).map_or(domain, String::as_str);
+        let server_name =
+            ServerName::try_from(name.to_owned()).map_err(|_| Error::InvalidDnsName {
+                domain: name.to_owned(),
+            })?;
+
+        let provider = crypto_provider();
+        let verifier = self.verifier(domain, provider.clone())?;
+
+        let builder = ClientConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .map_err(|err| Error::TlsConfig(err.into()))?
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(verifier));
+
+        let config = match &self.client_auth {
+            Some(auth) => builder
+                .with_client_auth_cert(auth.chain.clone(), auth.key.clone_key())
+                .map_err(|err| Error::TlsConfig(err.into()))?,
+            None => builder.with_no_client_auth(),
+        };
+
+        Ok((TlsConnector::from(Arc::new(config)), server_name))
+    }
+
+    fn verifier(&self, domain: &str, provider: Arc<CryptoProvider>) -> Result<Verifier, Error> {
+        let webpki = match self.dangerous_accept_invalid_certs {
+            true => None,
+            false => {
+                let mut roots = RootCertStore::empty();
+                if !self.replace_roots {
+                    roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+                }
+                for root in &self.roots {
+                    roots
+                        .add(root.clone())
+                        .map_err(|err| Error::TlsConfig(err.into()))?;
+                }
+
+                let verifier =
+                    WebPkiServerVerifier::builder_with_provider(Arc::new(roots), provider.clone())
+                        .build()
+                        .map_err(|err| Error::TlsConfig(err.into()))?;
+                Some(verifier)
+            }
+        };
+
+        Ok(Verifier {
+            webpki,
+            pins: self.pins.get(domain).cloned().unwrap_or_default(),
+            provider,
+        })
+    }
+}
+
+/// Computes pin of `certificate` for `TlsOptions::pins`
+pub fn spki_sha256(certificate: &CertificateDer) -> Result<SpkiPin, Error> {
+    let (_, parsed) = x509_parser::parse_x509_certificate(certificate)
+        .map_err(|err| Error::TlsConfig(err.into()))?;
+
+    Ok(Sha256::digest(parsed.tbs_certificate.subject_pki.raw).into())
+}
+
+fn parse_certificates(pem: &[u8]) -> Result<Vec<CertificateDer<'static>>, Error> {
+    let certificates = CertificateDer::pem_slice_iter(pem)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|err| Error::TlsConfig(err.into()))?;
+
+    if certificates.is_empty() {
+        return Err(Error::TlsConfig("no certificates found in PEM".into()));
+    }
+
+    Ok(certificates)
+}
+
+/// Process-wide provider if application installed one, otherwise the rustls default
+fn crypto_provider() -> Arc<CryptoProvider> {
+    CryptoProvider::get_default()
+        .cloned()
+        .unwrap_or_else(|| Arc::new(rustls::crypto::aws_lc_rs::default_provider()))
+}
+
+/// Webpki verification (unless disabled), followed by SPKI pin check
+#[derive(Debug)]
+struct Verifier {
+    webpki: Option<Arc<WebPkiServerVerifier>>,
+    pins: Vec<SpkiPin>,
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for Verifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if let Some(webpki) = &self.webpki {
+            webpki.verify_server_cert(
+                end_entity,
+                intermediates,
+                server_name,
+                ocsp_response,
+                now,
+            )?;
+        }
+
+        if !self.pins.is_empty() {
+            let pin = spki_sha256(end_entity)
+                .map_err(|_| rustls::Error::InvalidCertificate(CertificateError::BadEncoding))?;
+
+            if !self.pins.contains(&pin) {
+                return Err(rustls::Error::InvalidCertificate(
+                    CertificateError::ApplicationVerificationFailure,
+                ));
+            }
+        }
+
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Generated with openssl: P-256 CA, and `localhost` certificate it signed, both valid for 100 years
+    const CA_PEM: &str = "\
+-----BEGIN CERTIFICATE-----
+MIIBnTCCAUOgAwIBAgIUZH0nQjbQ5Re6mhreXD5YiHhI/Q8wCgYIKoZIzj0EAwIw
+GzEZMBcGA1UEAwwQZ2V0ZW1haWwgdGVzdCBDQTAgFw0yNjEwMTcwNTMxMjlaGA8y
+MTI2MDkyMzA1MzEyOVowGzEZMBcGA1UEAwwQZ2V0ZW1haWwgdGVzdCBDQTBZMBMG
+ByqGSM49AgEGCCqGSM49AwEHA0IABGAzXr9H8FVpOxXLzDrFnMJehwk5lFDHrVRj
+llIeYPfWijJqHdgpY9E0f86cCfFJZPAV20xo5yWtqDXTGVgbEL2jYzBhMB0GA1Ud
+DgQWBBS3YzPaUnSBMaf0i+pu0mBJsyTGczAfBgNVHSMEGDAWgBS3YzPaUnSBMaf0
+i+pu0mBJsyTGczAPBgNVHRMBAf8EBTADAQH/MA4GA1UdDwEB/wQEAwICBDAKBggq
+hkjOPQQDAgNIADBFAiEA8mNCaHyD3Kc++TiQ5dc2su76PF88DW7zrHtVGrHcnaMC
+IDidDtWeA2uitj1/YLOKChvhWtLNdQYJ/0EYXtOgefd6
+-----END CERTIFICATE-----
+";
+    const LEAF_PEM: &str = "\
+-----BEGIN CERTIFICATE-----
+MIIBrjCCAVSgAwIBAgIUDo78l6Re5tDMLaZU4vsklD8XMDgwCgYIKoZIzj0EAwIw
+GzEZMBcGA1UEAwwQZ2V0ZW1haWwgdGVzdCBDQTAgFw0yNjEwMTcwNTMxMjlaGA8y
+MTI2MDkyMzA1MzEyOVowFDESMBAGA1UEAwwJbG9jYWxob3N0MFkwEwYHKoZIzj0C
+AQYIKoZIzj0DAQcDQgAEH3JtT/1+zd+kMQyc49o5iLPk4nNhCMIQ59jWdMaz+wDn
+Be24VkF+T2fiwWBkbJQEPYXUGNZsFKxsEUQKycgdSqN7MHkwFAYDVR0RBA0wC4IJ
+bG9jYWxob3N0MAwGA1UdEwEB/wQCMAAwEwYDVR0lBAwwCgYIKwYBBQUHAwEwHQYD
+VR0OBBYEFKaS+Il+3sH4P+03zbiunficMytTMB8GA1UdIwQYMBaAFLdjM9pSdIEx
+p/SL6m7SYEmzJMZzMAoGCCqGSM49BAMCA0gAMEUCIAiG8e63rvZi40RZYQcLPGCP
+Yb/K0wAJtgm/zWqxolkFAiEA8ItGYbLbsn7wxr7lP2mnzH0oJ69iYVlBB7WNAZDg
+t+Q=
+-----END CERTIFICATE-----
+";
+
+    fn verify(options: &TlsOptions, domain: &str) -> Result<ServerCertVerified, rustls::Error> {
+        let leaf = parse_certificates(LEAF_PEM.as_bytes()).unwrap().remove(0);
+        let verifier = options.verifier(domain, crypto_provider()).unwrap();
+        let name = ServerName::try_from(domain.to_owned()).unwrap();
+
+        verifier.verify_server_cert(&leaf, &[], &name, &[], UnixTime::now())
+    }
+
+    fn is_unknown_issuer(result: Result<ServerCertVerified, rustls::Error>) -> bool {
+        matches!(
+            result,
+            Err(rustls::Error::InvalidCertificate(
+                CertificateError::UnknownIssuer
+            ))
+        )
+    }
+
+    #[test]
+    fn test_roots_pem() {
+        assert!(is_unknown_issuer(verify(
+            &TlsOptions::default(),
+            "localhost"
+        )));
+
+        let options = TlsOptions::default()
+            .with_roots_pem(CA_PEM.as_bytes())
+            .unwrap();
+        assert!(options.replace_roots);
+        assert_eq!(options.roots.len(), 1);
+        assert!(verify(&options, "localhost").is_ok());
+
+        let options = TlsOptions::default()
+            .with_extra_roots_pem(CA_PEM.as_bytes())
+            .unwrap();
+        assert!(!options.replace_roots);
+        assert!(verify(&options, "localhost").is_ok());
+
+        let err = TlsOptions::default()
+            .with_roots_pem(b"not a certificate")
+            .unwrap_err();
+        assert!(matches!(err, Error::TlsConfig(_)));
+    }
+
+    #[test]
+    fn test_pins() {
+        let leaf = parse_certificates(LEAF_PEM.as_bytes()).unwrap().remove(0);
+        let pin = spki_sha256(&leaf).unwrap();
+        let options = TlsOptions::default()
+            .with_roots_pem(CA_PEM.as_bytes())
+            .unwrap();
+
+        let pinned = options.clone().with_pin("localhost", pin);
+        assert!(verify(&pinned, "localhost").is_ok());
+
+        let mismatch = options.clone().with_pin("localhost", [0; 32]);
+        assert!(matches!(
+            verify(&mismatch, "localhost"),
+            Err(rustls::Error::InvalidCertificate(
+                CertificateError::ApplicationVerificationFailure
+            ))
+        ));
+
+        // Pins of other endpoints don't apply
+        let other = options.with_pin("mail.example.com", [0; 32]);
+        assert!(verify(&other, "localhost").is_ok());
+    }
+
+    #[test]
+    fn test_accept_invalid_certs() {
+        let options = TlsOptions::default().dangerous_accept_invalid_certs();
+        assert!(verify(&options, "localhost").is_ok());
+        // Certificate is issued for `localhost` only
+        assert!(verify(&options, "mail.example.com").is_ok());
+
+        let mismatch = options.with_pin("localhost", [0; 32]);
+        assert!(verify(&mismatch, "localhost").is_err());
+    }
+
+    #[test]
+    fn test_sni() {
+        let options = TlsOptions::default().with_sni("10.0.0.1", "mail.example.com");
+
+        let (_, name) = options.connector("10.0.0.1").unwrap();
+        assert_eq!(name.to_str(), "mail.example.com");
+
+        let (_, name) = options.connector("imap.example.org").unwrap();
+        assert_eq!(name.to_str(), "imap.example.org");
+    }
+}
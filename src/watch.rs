@@ -0,0 +1,66 @@
+use std::time::Duration;
+
+use futures::Stream;
+use proxied::Proxy;
+
+use crate::{
+    find_endpoints, imap_protocol::ImapConnector, mailbox_domain, server_map::ServerMap,
+    ConnectOptions, Error, Mailbox,
+};
+
+/// Change of the watched folder, reported by `watch`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum WatchEvent {
+    /// Number of messages in the folder. Always emitted first, then on every change
+    Exists(u32),
+    /// Connection was lost and restored. Changes made in between are reported with the next `Exists`
+    Reconnected,
+}
+
+#[derive(Clone, Debug)]
+pub struct WatchOptions {
+    /// Folder to watch
+    pub folder: String,
+    /// How often IDLE is reissued. Servers may drop IDLE after 29 minutes of inactivity (RFC 2177)
+    pub renew_interval: Duration,
+    /// Delay before the first reconnect attempt, multiplied by the attempt number for the next ones
+    pub reconnect_delay: Duration,
+    /// Stream ends with the last error after this many failed reconnects in a row,
+    /// or after this many IDLEs in a row failed despite successful reconnects
+    pub max_reconnect_attempts: u32,
+}
+
+impl Default for WatchOptions {
+    fn default() -> Self {
+        Self {
+            folder: "INBOX".to_owned(),
+            renew_interval: Duration::from_secs(25 * 60),
+            reconnect_delay: Duration::from_secs(5),
+            max_reconnect_attempts: 5,
+        }
+    }
+}
+
+/// Watch mailbox folder for new messages with IMAP IDLE
+///
+/// Keeps a single session open, so it's much cheaper than polling.
+/// Errors if mailbox has no IMAP endpoint in `ServerMap`
+pub async fn watch(
+    mailbox: Mailbox,
+    proxy: Option<Proxy>,
+    map: &ServerMap,
+    connect_options: &ConnectOptions,
+    options: WatchOptions,
+) -> Result<impl Stream<Item = Result<WatchEvent, Error>> + Send, Error> {
+    let domain = mailbox_domain(&mailbox)?;
+    let endpoints = find_endpoints(domain, map, connect_options).await?;
+    let Some(imap) = endpoints.get_imap() else {
+        return Err(Error::ImapNotFound {
+            domain: domain.to_owned(),
+        });
+    };
+
+    let imap = ImapConnector::connect(mailbox.clone(), imap, proxy, connect_options).await?;
+
+    Ok(imap.watch(options))
+}